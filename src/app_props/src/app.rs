@@ -1,13 +1,29 @@
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::BufReader;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 
 use trie_rs::{Trie, TrieBuilder};
+use db::batch::DEFAULT_BATCH_SIZE;
 use db::database::Database;
+use db::error::DbError;
+use img_azure::analyzer::{analyzer_from_config, ImageAnalyzer};
+use img_azure::config::{AnalyzerConfig, CallBudget};
+use crate::library::{library_path, DEFAULT_LIBRARY, LIBRARIES_DIR};
+use crate::usage::{move_library_usage, USAGE_PATH};
 use arc_str::arc_str::ArcStr;
 use vectorization::Embedding;
+use vectorization::embedder::TextEmbedder;
+use vectorization::index::{VectorIndex, HNSW_MIN_VECTORS};
+use vectorization::sentence::SentenceEmbedder;
+use vectorization::weighting::{english_stop_words, read_stop_words, TermStats, Weighting};
+pub const WORD_VECTORS_PATH: &str = "./glove.6B.300d.txt";
+pub const SENTENCE_MODEL_DIR: &str = "./sentence-model";
+/// Failures kept for the UI; older ones are dropped.
+pub const MAX_REPORTED_ERRORS: usize = 100;
+
 pub enum SomeTrie {
     Trie(Trie<u8>),
     TrieBuilder(TrieBuilder<u8>),
@@ -17,10 +33,22 @@ pub struct App {
     pub map: Arc<Mutex<HashMap<ArcStr, HashSet<ArcStr>>>>,
     pub trie: Arc<Mutex<SomeTrie>>,
     pub is_prefix_search_enabled: AtomicBool,
-    pub embeddings: Arc<Mutex<Embedding>>,
+    pub embedder: Arc<Mutex<Box<dyn TextEmbedder + Send>>>,
     pub db: Arc<Mutex<Option<Database>>>,
     pub is_image_search_enabled: AtomicBool,
-
+    pub vector_index: Arc<Mutex<Option<VectorIndex>>>,
+    pub search_top_k: AtomicUsize,
+    pub errors: Arc<Mutex<Vec<String>>>,
+    /// Images committed per transaction while indexing.
+    pub index_batch_size: AtomicUsize,
+    /// Name of the open photo library.
+    pub library: String,
+    /// Vision backend used to caption and tag images; `None` until image search is enabled.
+    pub analyzer: Option<Arc<dyn ImageAnalyzer>>,
+    /// Limits on requests to the vision service, from `analyzer` in `./config.json`.
+    pub budget: CallBudget,
+    /// Vision service requests of every library, in `./usage.db`; `None` until a library is opened.
+    pub usage_db: Arc<Mutex<Option<Database>>>,
 }
 
 impl App {
@@ -29,24 +57,29 @@ impl App {
             map: initialize_map(),
             trie: Arc::new(Mutex::new(SomeTrie::TrieBuilder(TrieBuilder::new()))),
             is_prefix_search_enabled: AtomicBool::new(false),
-            embeddings: Arc::new(Mutex::new(Embedding::new())),
+            embedder: Arc::new(Mutex::new(Box::new(Embedding::new()))),
             db: Arc::new(Mutex::new(None)),
             is_image_search_enabled: AtomicBool::new(false),
+            vector_index: Arc::new(Mutex::new(None)),
+            search_top_k: AtomicUsize::new(10),
+            errors: Arc::new(Mutex::new(Vec::new())),
+            index_batch_size: AtomicUsize::new(DEFAULT_BATCH_SIZE),
+            library: DEFAULT_LIBRARY.to_string(),
+            analyzer: None,
+            budget: CallBudget::default(),
+            usage_db: Arc::new(Mutex::new(None)),
         }
     }
 }
 pub fn enable_image_search(app: Arc<Mutex<App>>) {
-    let app_clone = app.clone();
     {
         let app = app.lock().unwrap();
-        let mut embeddings = app.embeddings.lock().unwrap();
-        embeddings.get_embeddings(r"./glove.6B.300d.txt");
+        *app.embedder.lock().unwrap() = load_embedder(&app.errors);
         print!("Embeddings initialized\n");
     }
-    {
-        let mut app = app_clone.lock().unwrap();
-        app.db = Arc::new(Mutex::new(Some(Database::new().unwrap())));
-        println!("Database initialized");
+    if let Err(e) = open_library(&app, DEFAULT_LIBRARY) {
+        report_error(&app.lock().unwrap().errors, format!("Unable to open library {}: {}", DEFAULT_LIBRARY, e));
+        return;
     }
     {
         let app = app.lock().unwrap();
@@ -54,6 +87,185 @@ pub fn enable_image_search(app: Arc<Mutex<App>>) {
         println!("Image search enabled");
     }
 }
+/// Makes `name` the open photo library, creating its database if needed, and loads its
+/// weighting and vectors. Indexing already running keeps writing to the previous library.
+pub fn open_library(app: &Arc<Mutex<App>>, name: &str) -> Result<(), DbError> {
+    if name != DEFAULT_LIBRARY {
+        if let Err(e) = std::fs::create_dir_all(LIBRARIES_DIR) {
+            println!("Unable to create {}: {}", LIBRARIES_DIR, e);
+        }
+    }
+    let mut db = Database::open(library_path(name))?;
+    {
+        let app = app.lock().unwrap();
+        let mut usage_db = app.usage_db.lock().unwrap();
+        if usage_db.is_none() {
+            *usage_db = Some(Database::open_usage(USAGE_PATH)?);
+        }
+        if let Err(e) = move_library_usage(&mut db, usage_db.as_mut().unwrap()) {
+            report_error(&app.errors, format!("Unable to move the vision requests of library {} to {}: {}", name, USAGE_PATH, e));
+        }
+    }
+    {
+        let mut app = app.lock().unwrap();
+        app.db = Arc::new(Mutex::new(Some(db)));
+        app.vector_index = Arc::new(Mutex::new(None));
+        app.library = name.to_string();
+        println!("Library {} opened", name);
+    }
+    {
+        let app = app.lock().unwrap();
+        let mut db = app.db.lock().unwrap();
+        let mut embedder = app.embedder.lock().unwrap();
+        // The weighting is part of the model id, so it is loaded before the id is checked.
+        if let Some(embeddings) = embedder.word_vectors() {
+            load_weighting(db.as_ref().unwrap(), embeddings)?;
+        }
+        check_index_model(db.as_mut().unwrap(), &**embedder)?;
+    }
+    rebuild_vector_index(app)
+}
+
+/// Uses the sentence-transformer model in `./sentence-model` when one is present,
+/// falling back to the GloVe word vectors otherwise. When those cannot be read either, the
+/// error is reported and no words are known until they are fixed.
+pub fn load_embedder(errors: &Arc<Mutex<Vec<String>>>) -> Box<dyn TextEmbedder + Send> {
+    if Path::new(SENTENCE_MODEL_DIR).join("config.json").exists() {
+        match SentenceEmbedder::load(SENTENCE_MODEL_DIR) {
+            Ok(embedder) => return Box::new(embedder),
+            Err(e) => println!("Unable to load sentence model, using word vectors: {}", e),
+        }
+    }
+    let mut embeddings = Embedding::new();
+    if let Err(e) = embeddings.get_embeddings(WORD_VECTORS_PATH) {
+        report_error(errors, format!("Unable to load word vectors: {}", e));
+    }
+    Box::new(embeddings)
+}
+
+/// Builds the vision backend selected by `analyzer.backend` in `./config.json`, with its call budget.
+pub fn load_analyzer() -> Result<(Arc<dyn ImageAnalyzer>, CallBudget), String> {
+    let config = AnalyzerConfig::load()?;
+    Ok((Arc::from(analyzer_from_config(&config)?), config.budget))
+}
+
+/// Loads the vision backend at startup. When it is not configured the app still runs,
+/// but indexing is refused and the reason is shown on the Image Index page.
+pub fn init_analyzer(app: &Arc<Mutex<App>>) {
+    match load_analyzer() {
+        Ok((analyzer, budget)) => {
+            println!("Image analyzer: {}", analyzer.name());
+            let mut app = app.lock().unwrap();
+            app.analyzer = Some(analyzer);
+            app.budget = budget;
+        }
+        Err(e) => report_error(&app.lock().unwrap().errors, format!("Image indexing disabled: {}", e)),
+    }
+}
+
+/// Prints a failure and keeps it for the UI, dropping the oldest past `MAX_REPORTED_ERRORS`.
+pub fn report_error(errors: &Arc<Mutex<Vec<String>>>, message: String) {
+    println!("Error: {}", message);
+    let mut errors = errors.lock().unwrap();
+    errors.push(message);
+    if errors.len() > MAX_REPORTED_ERRORS {
+        let excess = errors.len() - MAX_REPORTED_ERRORS;
+        errors.drain(..excess);
+    }
+}
+
+/// Loads every vector of the current model from the database into a fresh in-memory index.
+pub fn rebuild_vector_index(app: &Arc<Mutex<App>>) -> Result<(), DbError> {
+    let app = app.lock().unwrap();
+    let db = app.db.lock().unwrap();
+    let embedder = app.embedder.lock().unwrap();
+    let start = std::time::Instant::now();
+    let db = db.as_ref().ok_or(DbError::Closed)?;
+    let vectors = db.select_vectors_by_model(&embedder.model_id())?;
+    let dimensions = embedder.dimensions();
+    let (items, wrong): (Vec<_>, Vec<_>) = vectors.into_iter()
+        .map(|(id, _, vector)| (id, vector))
+        .partition(|(_, vector): &(u32, Vec<f32>)| vector.len() == dimensions);
+    if !wrong.is_empty() {
+        report_error(&app.errors, format!("{} images left out of the vector index: their vectors are not {} dims; re-embed them",
+                                          wrong.len(), dimensions));
+    }
+    let index = VectorIndex::build(dimensions, items, hnsw_min_vectors(db)?);
+    println!("Vector index built with {} images in {:?}", index.len(), start.elapsed());
+    *app.vector_index.lock().unwrap() = Some(index);
+    Ok(())
+}
+
+/// How many vectors the library needs before its index becomes an HNSW graph.
+pub fn hnsw_min_vectors(db: &Database) -> Result<usize, DbError> {
+    Ok(db.get_setting("hnsw_min_vectors")?
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(HNSW_MIN_VECTORS))
+}
+
+/// Claims an index without vectors from other models for the loaded model, or warns when the
+/// index was built with another one. Returns whether new vectors from `embedder` may be added.
+pub fn check_index_model(db: &mut Database, embedder: &dyn TextEmbedder) -> Result<bool, DbError> {
+    let model_id = embedder.model_id();
+    let dimensions = embedder.dimensions();
+    match db.index_model()? {
+        Some((stored_id, stored_dimensions)) => {
+            if stored_id != model_id || stored_dimensions != dimensions {
+                if db.select_images_outside_model(&model_id)?.is_empty() {
+                    db.set_index_model(&model_id, dimensions)?;
+                    return Ok(true);
+                }
+                println!("Index was built with {} ({} dims), loaded model is {} ({} dims); re-embed before indexing or searching",
+                         stored_id, stored_dimensions, model_id, dimensions);
+                return Ok(false);
+            }
+            Ok(true)
+        }
+        None => {
+            db.set_index_model(&model_id, dimensions)?;
+            let outside = db.select_images_outside_model(&model_id)?.len();
+            if outside > 0 {
+                println!("{} images have vectors that are not {} dims; re-embed them to make them searchable", outside, dimensions);
+            }
+            Ok(true)
+        }
+    }
+}
+
+/// Applies the weighting, stop words and term counts stored with the index to the embeddings.
+pub fn load_weighting(db: &Database, embeddings: &mut Embedding) -> Result<(), DbError> {
+    let weighting = db.get_setting("weighting")?
+        .and_then(|value| Weighting::parse(&value))
+        .unwrap_or(Weighting::Uniform);
+    embeddings.set_weighting(weighting);
+
+    let stop_words = db.get_setting("stop_words")?.unwrap_or("none".to_string());
+    match stop_words.as_str() {
+        "none" => embeddings.set_stop_words(Default::default()),
+        "english" => embeddings.set_stop_words(english_stop_words()),
+        path => match read_stop_words(path) {
+            Ok(words) => embeddings.set_stop_words(words),
+            Err(e) => println!("Unable to read stop words from {}: {}", path, e),
+        },
+    }
+
+    load_term_stats(db, embeddings)?;
+    println!("Weighting: {}, stop words: {}", weighting.as_str(), stop_words);
+    Ok(())
+}
+
+/// Replaces the term counts behind IDF and SIF weights with the ones stored in the index.
+pub fn load_term_stats(db: &Database, embeddings: &mut Embedding) -> Result<(), DbError> {
+    let mut term_stats = TermStats::new();
+    let (documents, terms) = db.select_term_stats()?;
+    term_stats.set_documents(documents);
+    for (term, document_count, term_count) in terms {
+        term_stats.insert(term, document_count, term_count);
+    }
+    embeddings.set_term_stats(term_stats);
+    Ok(())
+}
+
 pub fn initialize_map() -> Arc<Mutex<HashMap<ArcStr, HashSet<ArcStr>>>> {
     let mut map: HashMap<ArcStr, HashSet<ArcStr>> = HashMap::new();
     if let Ok(file) = File::open("./map.bin") {
@@ -1 +1,3 @@
 pub mod app;
+pub mod library;
+pub mod usage;
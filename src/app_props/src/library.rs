@@ -0,0 +1,42 @@
+use std::path::PathBuf;
+use db::database::DEFAULT_PATH;
+
+/// Directory holding one database file per named photo library.
+pub const LIBRARIES_DIR: &str = "./libraries";
+/// The library opened when image search is enabled. It keeps using `./database.db`
+/// so indexes created before libraries existed stay where they are.
+pub const DEFAULT_LIBRARY: &str = "default";
+
+/// Library names become file names, so only letters, digits, `-` and `_` are allowed.
+pub fn is_valid_library_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 64 && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+pub fn library_path(name: &str) -> PathBuf {
+    if name == DEFAULT_LIBRARY {
+        PathBuf::from(DEFAULT_PATH)
+    } else {
+        PathBuf::from(LIBRARIES_DIR).join(format!("{}.db", name))
+    }
+}
+
+/// The default library followed by every `<name>.db` in `./libraries`, sorted by name.
+pub fn list_libraries() -> Vec<String> {
+    let mut names = Vec::new();
+    if let Ok(entries) = std::fs::read_dir(LIBRARIES_DIR) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("db") {
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                if is_valid_library_name(name) && name != DEFAULT_LIBRARY {
+                    names.push(name.to_string());
+                }
+            }
+        }
+    }
+    names.sort();
+    names.insert(0, DEFAULT_LIBRARY.to_string());
+    names
+}
@@ -0,0 +1,175 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use db::database::Database;
+use db::error::DbError;
+use db::usage::{today, ApiUsage};
+use img_azure::analyzer::{ImageAnalyzer, RequestGate, Usage};
+use img_azure::config::CallBudget;
+use crate::app::report_error;
+
+/// Vision service requests of every library are counted here, so the call budget is shared
+/// by all of them.
+pub const USAGE_PATH: &str = "./usage.db";
+
+/// Counts the vision service requests of one indexing run in the shared usage database and
+/// pauses the run when the call budget is used up. Every request, retries included, is
+/// reserved before it is sent, so concurrent workers cannot go over the budget.
+pub struct UsageMeter {
+    analyzer: Arc<dyn ImageAnalyzer>,
+    db: Arc<Mutex<Option<Database>>>,
+    budget: CallBudget,
+    errors: Arc<Mutex<Vec<String>>>,
+    run_id: u32,
+    /// Analyzer totals already written to the database.
+    recorded: Mutex<Usage>,
+    paused: AtomicBool,
+}
+
+impl UsageMeter {
+    pub fn start(analyzer: Arc<dyn ImageAnalyzer>, db: Arc<Mutex<Option<Database>>>, budget: CallBudget,
+                 errors: Arc<Mutex<Vec<String>>>) -> Result<UsageMeter, DbError> {
+        let run_id = db.lock().unwrap().as_mut().ok_or(DbError::Closed)?.start_usage_run()?;
+        let recorded = Mutex::new(analyzer.usage());
+        Ok(UsageMeter {
+            analyzer,
+            db,
+            budget,
+            errors,
+            run_id,
+            recorded,
+            paused: AtomicBool::new(false),
+        })
+    }
+
+    /// Writes the successes and failures since the last call. Requests were counted when
+    /// they were reserved.
+    pub fn record(&self) {
+        let mut recorded = self.recorded.lock().unwrap();
+        let now = self.analyzer.usage();
+        let added = now.since(&recorded);
+        let usage = ApiUsage { requests: 0, successes: added.successes, failures: added.failures };
+        if usage.is_empty() {
+            return;
+        }
+        let saved = match self.db.lock().unwrap().as_mut() {
+            Some(db) => db.add_usage(self.run_id, &today(), &usage),
+            None => Err(DbError::Closed),
+        };
+        match saved {
+            Ok(()) => *recorded = now,
+            Err(e) => report_error(&self.errors, format!("Unable to record vision requests: {}", e)),
+        }
+    }
+
+    /// Whether the run may go on analysing images. The first time the budget is found used
+    /// up, or cannot be read, the run is paused and the reason is reported.
+    pub fn allows_request(&self) -> bool {
+        if self.paused.load(Ordering::Relaxed) {
+            return false;
+        }
+        match budget_exhausted(&self.db, &self.budget) {
+            Ok(None) => true,
+            Ok(Some(reason)) => self.pause(reason),
+            Err(e) => self.pause(format!("unable to check the vision budget: {}", e)),
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Pauses the run, reporting `reason` only the first time. Always returns `false`.
+    fn pause(&self, reason: String) -> bool {
+        if !self.paused.swap(true, Ordering::Relaxed) {
+            report_error(&self.errors, format!("Indexing paused: {}", reason));
+        }
+        false
+    }
+}
+
+impl RequestGate for UsageMeter {
+    /// Checks the budget and counts the request in one step while holding the database, so
+    /// no two workers can claim the last request.
+    fn reserve(&self) -> Result<(), String> {
+        if self.is_paused() {
+            return Err("indexing is paused".to_string());
+        }
+        let mut db = self.db.lock().unwrap();
+        let reserved = match db.as_mut() {
+            Some(db) => reserve_request(db, self.run_id, &self.budget),
+            None => Err(DbError::Closed),
+        };
+        drop(db);
+        match reserved {
+            Ok(None) => Ok(()),
+            Ok(Some(reason)) => {
+                self.pause(reason.clone());
+                Err(reason)
+            }
+            Err(e) if self.budget.daily.is_none() && self.budget.monthly.is_none() => {
+                report_error(&self.errors, format!("Unable to record vision requests: {}", e));
+                Ok(())
+            }
+            Err(e) => {
+                let reason = format!("unable to check the vision budget: {}", e);
+                self.pause(reason.clone());
+                Err(reason)
+            }
+        }
+    }
+}
+
+/// Counts one request for `run_id` and today, unless a budget is used up; then says which.
+fn reserve_request(db: &mut Database, run_id: u32, budget: &CallBudget) -> Result<Option<String>, DbError> {
+    if let Some(reason) = exhausted_budget(db, budget)? {
+        return Ok(Some(reason));
+    }
+    db.add_usage(run_id, &today(), &ApiUsage { requests: 1, successes: 0, failures: 0 })?;
+    Ok(None)
+}
+
+/// Which budget is used up according to the totals in the database, if any.
+pub fn budget_exhausted(db: &Arc<Mutex<Option<Database>>>, budget: &CallBudget) -> Result<Option<String>, DbError> {
+    if budget.daily.is_none() && budget.monthly.is_none() {
+        return Ok(None);
+    }
+    let db = db.lock().unwrap();
+    exhausted_budget(db.as_ref().ok_or(DbError::Closed)?, budget)
+}
+
+fn exhausted_budget(db: &Database, budget: &CallBudget) -> Result<Option<String>, DbError> {
+    if budget.daily.is_none() && budget.monthly.is_none() {
+        return Ok(None);
+    }
+    let day = today();
+    if let Some(daily) = budget.daily {
+        let used = db.select_usage(&day)?.requests;
+        if used >= daily {
+            return Ok(Some(format!("daily budget of {} vision requests reached ({} used on {})", daily, used, day)));
+        }
+    }
+    if let Some(monthly) = budget.monthly {
+        let month = &day[..7];
+        let used = db.select_usage(month)?.requests;
+        if used >= monthly {
+            return Ok(Some(format!("monthly budget of {} vision requests reached ({} used in {})", monthly, used, month)));
+        }
+    }
+    Ok(None)
+}
+
+/// Moves the request totals a library recorded before usage was shared into the shared
+/// database. Its usage tables are dropped only once copied, so a failure can count them
+/// twice but never lose them.
+pub fn move_library_usage(library: &mut Database, shared: &mut Database) -> Result<(), DbError> {
+    if !library.has_usage_tables()? {
+        return Ok(());
+    }
+    let days = library.select_usage_days()?;
+    for (day, usage) in days.iter() {
+        shared.add_day_usage(day, usage)?;
+    }
+    library.drop_usage_tables()?;
+    println!("Moved vision requests of {} days to {}", days.len(), USAGE_PATH);
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use std::sync::{Arc, Mutex};
+use app_props::usage::{move_library_usage, UsageMeter};
+use db::database::Database;
+use db::usage::{today, ApiUsage};
+use img_azure::analyzer::{AzureAnalyzer, ImageAnalyzer, RequestGate};
+use img_azure::config::{AzureConfig, CallBudget};
+
+/// Never sent a request; the meter only reads its totals.
+fn analyzer() -> Arc<dyn ImageAnalyzer> {
+    Arc::new(AzureAnalyzer::new(AzureConfig {
+        endpoint: "http://127.0.0.1:9".to_string(),
+        key: "unused".to_string(),
+        ..AzureConfig::default()
+    }).unwrap())
+}
+
+fn meter(db: &Arc<Mutex<Option<Database>>>, daily: Option<u64>) -> (UsageMeter, Arc<Mutex<Vec<String>>>) {
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let budget = CallBudget { daily, monthly: None };
+    (UsageMeter::start(analyzer(), db.clone(), budget, errors.clone()).unwrap(), errors)
+}
+
+#[test]
+fn requests_are_counted_when_reserved_until_the_budget_is_used_up() {
+    let db = Arc::new(Mutex::new(Some(Database::usage_in_memory().unwrap())));
+    let (meter, errors) = meter(&db, Some(3));
+
+    for _ in 0..3 {
+        assert!(meter.reserve().is_ok());
+    }
+    assert!(meter.reserve().is_err());
+    assert!(meter.reserve().is_err());
+
+    assert!(meter.is_paused());
+    assert!(!meter.allows_request());
+    assert_eq!(db.lock().unwrap().as_ref().unwrap().select_usage(&today()).unwrap().requests, 3);
+    assert_eq!(errors.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn meters_of_different_libraries_share_the_budget() {
+    let db = Arc::new(Mutex::new(Some(Database::usage_in_memory().unwrap())));
+    let (first, _) = meter(&db, Some(2));
+    let (second, _) = meter(&db, Some(2));
+
+    assert!(first.reserve().is_ok());
+    assert!(second.reserve().is_ok());
+    assert!(first.reserve().is_err());
+    assert!(!second.allows_request());
+}
+
+#[test]
+fn an_unreadable_budget_refuses_requests() {
+    let db = Arc::new(Mutex::new(Some(Database::usage_in_memory().unwrap())));
+    let (meter, errors) = meter(&db, Some(100));
+    db.lock().unwrap().take().unwrap().close().unwrap();
+
+    assert!(meter.reserve().is_err());
+    assert!(!meter.allows_request());
+    assert!(errors.lock().unwrap()[0].starts_with("Indexing paused: unable to check the vision budget"));
+}
+
+#[test]
+fn without_a_budget_an_unrecorded_request_is_still_sent() {
+    let db = Arc::new(Mutex::new(Some(Database::usage_in_memory().unwrap())));
+    let (meter, errors) = meter(&db, None);
+    db.lock().unwrap().take().unwrap().close().unwrap();
+
+    assert!(meter.reserve().is_ok());
+    assert!(meter.allows_request());
+    assert_eq!(errors.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn library_usage_moves_to_the_shared_database() {
+    let mut library = Database::in_memory().unwrap();
+    assert!(!library.has_usage_tables().unwrap());
+    // The tables a library had while it counted its own requests.
+    library.connection().unwrap().execute_batch(
+        "CREATE TABLE api_usage_runs (id INTEGER PRIMARY KEY, started_at INTEGER NOT NULL, requests INTEGER NOT NULL,
+                                      successes INTEGER NOT NULL, failures INTEGER NOT NULL);
+        CREATE TABLE api_usage_days (day TEXT PRIMARY KEY, requests INTEGER NOT NULL, successes INTEGER NOT NULL,
+                                     failures INTEGER NOT NULL);",
+    ).unwrap();
+    let run = library.start_usage_run().unwrap();
+    library.add_usage(run, "2024-05-31", &ApiUsage { requests: 3, successes: 3, failures: 0 }).unwrap();
+    let mut shared = Database::usage_in_memory().unwrap();
+    shared.add_day_usage("2024-05-31", &ApiUsage { requests: 2, successes: 1, failures: 1 }).unwrap();
+
+    move_library_usage(&mut library, &mut shared).unwrap();
+    move_library_usage(&mut library, &mut shared).unwrap();
+
+    assert!(!library.has_usage_tables().unwrap());
+    assert_eq!(shared.select_usage("2024-05").unwrap(), ApiUsage { requests: 5, successes: 4, failures: 1 });
+}
@@ -0,0 +1,164 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use rusqlite::Connection;
+use crate::error::DbError;
+use crate::text_search::index_text;
+
+#[derive(Debug, Clone)]
+pub struct Tag {
+    pub name: String,
+    pub confidence: f64,
+}
+
+/// Whether a region is a detected object or a densely captioned part of the image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegionKind {
+    Object,
+    Caption,
+}
+
+impl RegionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RegionKind::Object => "object",
+            RegionKind::Caption => "caption",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<RegionKind> {
+        match kind {
+            "object" => Some(RegionKind::Object),
+            "caption" => Some(RegionKind::Caption),
+            _ => None,
+        }
+    }
+}
+
+/// A part of an image. The box is given as fractions of the image's width and height.
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub kind: RegionKind,
+    /// The object's name or the region's caption.
+    pub name: String,
+    pub confidence: f64,
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+/// What the vision service said about an image, kept so vectors can be rebuilt
+/// without analysing the image again.
+#[derive(Debug, Clone)]
+pub struct ImageAnalysis {
+    pub image_id: u32,
+    pub caption: String,
+    pub tags: Vec<Tag>,
+    /// Lines of text read from the image, top to bottom; empty when there is none.
+    pub lines: Vec<String>,
+    /// Detected objects and captioned regions.
+    pub regions: Vec<Region>,
+    /// Seconds since the Unix epoch.
+    pub analyzed_at: i64,
+}
+
+impl ImageAnalysis {
+    pub fn new(image_id: u32, caption: String, tags: Vec<Tag>) -> ImageAnalysis {
+        let analyzed_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+        ImageAnalysis {
+            image_id,
+            caption,
+            tags,
+            lines: Vec::new(),
+            regions: Vec::new(),
+            analyzed_at,
+        }
+    }
+
+    pub fn set_lines(&mut self, lines: Vec<String>) {
+        self.lines = lines;
+    }
+
+    pub fn set_regions(&mut self, regions: Vec<Region>) {
+        self.regions = regions;
+    }
+
+    /// The caption followed by the captions of regions, as indexed for keyword search.
+    pub fn searchable_caption(&self) -> String {
+        let mut captions = vec![self.caption.as_str()];
+        captions.extend(self.regions.iter()
+            .filter(|region| region.kind == RegionKind::Caption)
+            .map(|region| region.name.as_str()));
+        captions.join("\n")
+    }
+
+    /// Tag names ordered by confidence, best first.
+    pub fn top_tags(&self, count: usize) -> Vec<String> {
+        let mut tags = self.tags.clone();
+        tags.sort_by(|a, b| b.confidence.partial_cmp(&a.confidence).unwrap_or(std::cmp::Ordering::Equal));
+        tags.into_iter().take(count).map(|tag| tag.name).collect()
+    }
+}
+
+impl ImageAnalysis {
+    /// Writes the analysis, its tags, text lines, regions and full-text entry on `connection`
+    /// without opening a transaction of its own.
+    pub fn insert(&self, connection: &Connection) -> Result<u32, DbError> {
+        connection.prepare_cached(
+            "INSERT OR REPLACE INTO image_analyses (image_id, caption, analyzed_at) VALUES (?1, ?2, ?3)",
+        )?.execute((self.image_id, &self.caption, self.analyzed_at))?;
+        connection.prepare_cached("DELETE FROM image_tags WHERE image_id = ?1")?.execute([self.image_id])?;
+        let mut insert_tag = connection.prepare_cached(
+            "INSERT INTO image_tags (image_id, name, confidence) VALUES (?1, ?2, ?3)",
+        )?;
+        for tag in self.tags.iter() {
+            insert_tag.execute((self.image_id, &tag.name, tag.confidence))?;
+        }
+        connection.prepare_cached("DELETE FROM image_lines WHERE image_id = ?1")?.execute([self.image_id])?;
+        let mut insert_line = connection.prepare_cached(
+            "INSERT INTO image_lines (image_id, line, text) VALUES (?1, ?2, ?3)",
+        )?;
+        for (line, text) in self.lines.iter().enumerate() {
+            insert_line.execute((self.image_id, line as u32, text))?;
+        }
+        connection.prepare_cached("DELETE FROM image_regions WHERE image_id = ?1")?.execute([self.image_id])?;
+        let mut insert_region = connection.prepare_cached(
+            "INSERT INTO image_regions (image_id, kind, name, confidence, x, y, width, height)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        )?;
+        for region in self.regions.iter() {
+            insert_region.execute((
+                self.image_id, region.kind.as_str(), &region.name, region.confidence,
+                region.x, region.y, region.width, region.height,
+            ))?;
+        }
+        let names: Vec<String> = self.tags.iter().map(|tag| tag.name.clone()).collect();
+        index_text(connection, self.image_id, &self.searchable_caption(), &names, &self.lines)?;
+        Ok(self.image_id)
+    }
+}
+
+impl crate::database::Save for ImageAnalysis {
+    fn save(&mut self, connection: &mut Connection) -> Result<u32, DbError> {
+        let tx = connection.transaction()?;
+        self.insert(&tx)?;
+        tx.commit()?;
+        Ok(self.image_id)
+    }
+}
+
+/// Maps a `kind, name, confidence, x, y, width, height` row; rows of unknown kinds give `None`.
+pub(crate) fn region_from_row(row: &rusqlite::Row) -> Result<Option<Region>, rusqlite::Error> {
+    let kind = match RegionKind::parse(&row.get::<usize, String>(0)?) {
+        Some(kind) => kind,
+        None => return Ok(None),
+    };
+    Ok(Some(Region {
+        kind,
+        name: row.get(1)?,
+        confidence: row.get(2)?,
+        x: row.get(3)?,
+        y: row.get(4)?,
+        width: row.get(5)?,
+        height: row.get(6)?,
+    }))
+}
@@ -0,0 +1,159 @@
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use crate::analysis::ImageAnalysis;
+use crate::cache::store_cached_analysis;
+use crate::database::{count_terms, Database};
+use crate::error::DbError;
+use crate::image::Image;
+
+pub const DEFAULT_BATCH_SIZE: usize = 256;
+/// Longest an image waits in a partly filled batch before the batch is committed anyway.
+pub const BATCH_LINGER: Duration = Duration::from_millis(500);
+
+/// An analysed image waiting to be written, with its analysis and the tokens counted
+/// for term statistics. `analysis.image_id` is filled in once the image is inserted.
+#[derive(Debug)]
+pub struct PendingImage {
+    pub image: Image,
+    pub analysis: ImageAnalysis,
+    pub tokens: Vec<String>,
+}
+
+impl Database {
+    /// Writes every pending image, its analysis, its cache entry and its term counts in one transaction.
+    /// Nothing is written if any of them fails.
+    pub fn save_batch(&mut self, batch: &mut [PendingImage]) -> Result<(), DbError> {
+        let tx = self.connection_mut()?.transaction()?;
+        for pending in batch.iter_mut() {
+            pending.analysis.image_id = pending.image.insert(&tx)?;
+            pending.analysis.insert(&tx)?;
+            if let Some(content_hash) = pending.image.content_hash.as_deref() {
+                store_cached_analysis(&tx, content_hash, &pending.analysis)?;
+            }
+            count_terms(&tx, pending.analysis.image_id, &pending.tokens)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+/// Outcome of one committed batch: the images that were saved and the ones that failed.
+pub struct BatchOutcome {
+    pub saved: Vec<PendingImage>,
+    pub failed: Vec<(PendingImage, DbError)>,
+}
+
+/// Write-behind queue for indexing. Workers `send` analysed images without touching the
+/// database lock; a background thread commits them in transactions of up to `batch_size`
+/// images and hands each outcome to `on_commit`.
+///
+/// When a batch fails as a whole its images are retried one transaction each, so a
+/// single bad row only loses itself.
+pub struct BatchWriter {
+    sender: Option<Sender<PendingImage>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BatchWriter {
+    pub fn new<F>(db: Arc<Mutex<Option<Database>>>, batch_size: usize, mut on_commit: F) -> BatchWriter
+    where
+        F: FnMut(BatchOutcome) + Send + 'static,
+    {
+        let batch_size = batch_size.max(1);
+        let (sender, receiver) = channel::<PendingImage>();
+        let handle = std::thread::spawn(move || {
+            let mut batch = Vec::with_capacity(batch_size);
+            let mut deadline: Option<Instant> = None;
+            loop {
+                let timeout = match deadline {
+                    Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                    None => Duration::from_secs(3600),
+                };
+                let closed = match receiver.recv_timeout(timeout) {
+                    Ok(pending) => {
+                        if batch.is_empty() {
+                            deadline = Some(Instant::now() + BATCH_LINGER);
+                        }
+                        batch.push(pending);
+                        false
+                    }
+                    Err(RecvTimeoutError::Timeout) => false,
+                    Err(RecvTimeoutError::Disconnected) => true,
+                };
+                let due = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+                if !batch.is_empty() && (batch.len() >= batch_size || due || closed) {
+                    let outcome = commit(&db, std::mem::take(&mut batch));
+                    on_commit(outcome);
+                    deadline = None;
+                }
+                if closed {
+                    break;
+                }
+            }
+        });
+        BatchWriter {
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+
+    /// Queues an image. Fails with `DbError::Closed` once the writer has shut down.
+    pub fn send(&self, pending: PendingImage) -> Result<(), DbError> {
+        match self.sender.as_ref() {
+            Some(sender) => sender.send(pending).map_err(|_| DbError::Closed),
+            None => Err(DbError::Closed),
+        }
+    }
+
+    /// Commits whatever is still queued and waits for the writer thread to stop.
+    pub fn finish(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                println!("Error: batch writer thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for BatchWriter {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn commit(db: &Arc<Mutex<Option<Database>>>, mut batch: Vec<PendingImage>) -> BatchOutcome {
+    let mut db = db.lock().unwrap();
+    let db = match db.as_mut() {
+        Some(db) => db,
+        None => {
+            return BatchOutcome {
+                saved: Vec::new(),
+                failed: batch.into_iter().map(|pending| (pending, DbError::Closed)).collect(),
+            };
+        }
+    };
+    if db.save_batch(&mut batch).is_ok() {
+        return BatchOutcome { saved: batch, failed: Vec::new() };
+    }
+
+    let mut saved = Vec::new();
+    let mut failed = Vec::new();
+    for pending in batch {
+        let mut single = [pending];
+        match db.save_batch(&mut single) {
+            Ok(()) => saved.extend(single),
+            Err(e) => {
+                let [pending] = single;
+                failed.push((pending, e));
+            }
+        }
+    }
+    BatchOutcome { saved, failed }
+}
@@ -0,0 +1,115 @@
+use rusqlite::Connection;
+use crate::analysis::{region_from_row, ImageAnalysis, Region, Tag};
+use crate::database::Database;
+use crate::error::DbError;
+use crate::semantic_vector::SemanticVec;
+
+/// Remembers `analysis` for any image whose contents hash to `content_hash`, replacing an
+/// earlier entry. Entries outlive the images they came from, so a moved photo finds its
+/// analysis even after the old path was removed.
+pub fn store_cached_analysis(connection: &Connection, content_hash: &str, analysis: &ImageAnalysis) -> Result<(), DbError> {
+    connection.prepare_cached(
+        "INSERT OR REPLACE INTO analysis_cache (content_hash, caption, analyzed_at) VALUES (?1, ?2, ?3)",
+    )?.execute((content_hash, &analysis.caption, analysis.analyzed_at))?;
+    connection.prepare_cached("DELETE FROM analysis_cache_tags WHERE content_hash = ?1")?.execute([content_hash])?;
+    let mut insert_tag = connection.prepare_cached(
+        "INSERT OR REPLACE INTO analysis_cache_tags (content_hash, name, confidence) VALUES (?1, ?2, ?3)",
+    )?;
+    for tag in analysis.tags.iter() {
+        insert_tag.execute((content_hash, &tag.name, tag.confidence))?;
+    }
+    connection.prepare_cached("DELETE FROM analysis_cache_lines WHERE content_hash = ?1")?.execute([content_hash])?;
+    let mut insert_line = connection.prepare_cached(
+        "INSERT INTO analysis_cache_lines (content_hash, line, text) VALUES (?1, ?2, ?3)",
+    )?;
+    for (line, text) in analysis.lines.iter().enumerate() {
+        insert_line.execute((content_hash, line as u32, text))?;
+    }
+    connection.prepare_cached("DELETE FROM analysis_cache_regions WHERE content_hash = ?1")?.execute([content_hash])?;
+    let mut insert_region = connection.prepare_cached(
+        "INSERT INTO analysis_cache_regions (content_hash, kind, name, confidence, x, y, width, height)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    )?;
+    for region in analysis.regions.iter() {
+        insert_region.execute((
+            content_hash, region.kind.as_str(), &region.name, region.confidence,
+            region.x, region.y, region.width, region.height,
+        ))?;
+    }
+    Ok(())
+}
+
+impl Database {
+    pub fn cache_analysis(&mut self, content_hash: &str, analysis: &ImageAnalysis) -> Result<(), DbError> {
+        let tx = self.connection_mut()?.transaction()?;
+        store_cached_analysis(&tx, content_hash, analysis)?;
+        Ok(tx.commit()?)
+    }
+
+    /// The cached analysis for these contents, with `image_id` left at 0.
+    pub fn select_cached_analysis(&self, content_hash: &str) -> Result<Option<ImageAnalysis>, DbError> {
+        let connection = self.connection()?;
+        let mut statement = connection.prepare_cached("SELECT caption, analyzed_at FROM analysis_cache WHERE content_hash = ?1")?;
+        let mut rows = statement.query([content_hash])?;
+        let (caption, analyzed_at): (String, i64) = match rows.next()? {
+            Some(row) => (row.get(0)?, row.get(1)?),
+            None => return Ok(None),
+        };
+
+        let mut statement = connection.prepare_cached(
+            "SELECT name, confidence FROM analysis_cache_tags WHERE content_hash = ?1 ORDER BY confidence DESC",
+        )?;
+        let tags = statement.query_map([content_hash], |row| Ok(Tag { name: row.get(0)?, confidence: row.get(1)? }))?
+            .collect::<Result<Vec<Tag>, rusqlite::Error>>()?;
+        let mut statement = connection.prepare_cached(
+            "SELECT text FROM analysis_cache_lines WHERE content_hash = ?1 ORDER BY line",
+        )?;
+        let lines = statement.query_map([content_hash], |row| row.get(0))?
+            .collect::<Result<Vec<String>, rusqlite::Error>>()?;
+        let mut statement = connection.prepare_cached(
+            "SELECT kind, name, confidence, x, y, width, height FROM analysis_cache_regions WHERE content_hash = ?1 ORDER BY id",
+        )?;
+        let regions = statement.query_map([content_hash], region_from_row)?
+            .collect::<Result<Vec<Option<Region>>, rusqlite::Error>>()?
+            .into_iter().flatten().collect();
+        Ok(Some(ImageAnalysis {
+            image_id: 0,
+            caption,
+            tags,
+            lines,
+            regions,
+            analyzed_at,
+        }))
+    }
+
+    /// A vector already computed with `model_id` for another image with the same contents.
+    pub fn select_vector_by_hash(&self, content_hash: &str, model_id: &str) -> Result<Option<Vec<f32>>, DbError> {
+        let mut statement = self.connection()?.prepare_cached(
+            "SELECT vector FROM images WHERE content_hash = ?1 AND model_id = ?2 AND vector IS NOT NULL LIMIT 1",
+        )?;
+        let mut rows = statement.query((content_hash, model_id))?;
+        match rows.next()? {
+            Some(row) => {
+                let vector: Vec<u8> = row.get(0)?;
+                Ok(Some(SemanticVec::from_blob(&vector).0))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn select_content_hash(&self, image_id: u32) -> Result<Option<String>, DbError> {
+        let mut statement = self.connection()?.prepare_cached("SELECT content_hash FROM images WHERE id = ?1")?;
+        let mut rows = statement.query([image_id])?;
+        match rows.next()? {
+            Some(row) => Ok(row.get(0)?),
+            None => Ok(None),
+        }
+    }
+
+    /// Records the hash of an image indexed before hashes were stored.
+    pub fn set_content_hash(&mut self, image_id: u32, content_hash: &str) -> Result<(), DbError> {
+        self.connection()?.prepare_cached("UPDATE images SET content_hash = ?1 WHERE id = ?2")?
+            .execute((content_hash, image_id))?;
+        Ok(())
+    }
+}
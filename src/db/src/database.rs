@@ -1,7 +1,12 @@
-use std::mem::forget;
+use std::collections::HashMap;
+use std::path::Path;
 use rusqlite::{Connection};
+use crate::analysis::{region_from_row, ImageAnalysis, Region, Tag};
+use crate::error::DbError;
 use crate::image::Image;
-use crate::semantic_vector::{SemanticVec, SemanticVectorElement};
+use crate::migrations::{migrate_with, Migration, MIGRATIONS, USAGE_MIGRATIONS};
+use crate::semantic_vector::SemanticVec;
+use crate::text_search::remove_text;
 
 
 #[derive(Debug)]
@@ -9,210 +14,335 @@ pub struct Database {
     pub connection: Option<Connection>,
 }
 
+/// A `term_stats` row: `(term, document_count, term_count)`.
+pub type TermCount = (String, u32, u32);
+
+/// Where `Database::new` keeps the database.
+pub const DEFAULT_PATH: &str = "./database.db";
+
 impl Database {
-    pub fn new() -> Result<Database, rusqlite::Error> {
-        let connection = Connection::open("./database.db").expect("Connection::open");
-
-        match connection
-            .execute(
-                "CREATE TABLE IF NOT EXISTS images (
-                    id INTEGER PRIMARY KEY,
-                    path TEXT NOT NULL UNIQUE,
-                    title TEXT NOT NULL
-                )",
-                [],
-            ) {
-            Ok(_) => (),
-            Err(e) => return Err(e),
-        };
-        match connection
-            .execute(
-                "CREATE INDEX IF NOT EXISTS index_images_on_path ON images (path)",
-                [],
-            ) {
-            Ok(_) => (),
-            Err(e) => return Err(e),
-        };
-        match connection
-            .execute(
-                "CREATE TABLE IF NOT EXISTS semantic_vectors (
-                        id INTEGER PRIMARY KEY,
-                        image_id INTEGER,
-                        value REAL NOT NULL,
-                        FOREIGN KEY(image_id) REFERENCES images(id)
-                    )",
-                [],
-            ) {
-            Ok(_) => (),
-            Err(e) => return Err(e),
-        };
-        match connection
-            .execute(
-                "CREATE INDEX IF NOT EXISTS index_semantic_vectors_on_image_id ON semantic_vectors (image_id)",
-                [],
-            ) {
-            Ok(_) => (),
-            Err(e) => return Err(e),
-        };
+    /// Opens `./database.db` and applies any pending schema migrations.
+    pub fn new() -> Result<Database, DbError> {
+        Database::open(DEFAULT_PATH)
+    }
+
+    /// Opens or creates the database file at `path` and applies any pending schema migrations.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Database, DbError> {
+        Database::open_with(path, MIGRATIONS)
+    }
+
+    /// Opens or creates the usage database shared by all libraries, which only has the
+    /// `api_usage_*` tables.
+    pub fn open_usage<P: AsRef<Path>>(path: P) -> Result<Database, DbError> {
+        Database::open_with(path, USAGE_MIGRATIONS)
+    }
+
+    fn open_with<P: AsRef<Path>>(path: P, migrations: &[Migration]) -> Result<Database, DbError> {
+        let connection = Connection::open(path)?;
+        // WAL lets searches read while indexing writes, and NORMAL sync is safe with WAL.
+        connection.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        Database::from_connection(connection, migrations)
+    }
+
+    /// A fresh, fully migrated database that lives only as long as this value.
+    pub fn in_memory() -> Result<Database, DbError> {
+        Database::from_connection(Connection::open_in_memory()?, MIGRATIONS)
+    }
+
+    /// A fresh usage database that lives only as long as this value.
+    pub fn usage_in_memory() -> Result<Database, DbError> {
+        Database::from_connection(Connection::open_in_memory()?, USAGE_MIGRATIONS)
+    }
+
+    fn from_connection(mut connection: Connection, migrations: &[Migration]) -> Result<Database, DbError> {
+        connection.set_prepared_statement_cache_capacity(64);
+        migrate_with(&mut connection, migrations)?;
         Ok(Database { connection: Some(connection) })
     }
+
+    pub fn connection(&self) -> Result<&Connection, DbError> {
+        self.connection.as_ref().ok_or(DbError::Closed)
+    }
+
+    pub fn connection_mut(&mut self) -> Result<&mut Connection, DbError> {
+        self.connection.as_mut().ok_or(DbError::Closed)
+    }
 }
 
+/// Closes the connection if `close()` was not called. Errors cannot be returned from
+/// here, so they are only printed; call `close()` to handle them.
 impl Drop for Database {
     fn drop(&mut self) {
-        self.connection.take().unwrap().close().expect("Connection::close");
-        panic!("Should be closed explicitly with close() method");
+        if let Some(connection) = self.connection.take() {
+            if let Err((_, e)) = connection.close() {
+                println!("Error closing database: {:?}", e);
+            }
+        }
     }
 }
 
 impl Database {
-    pub fn close(mut self) {
-        self.connection.take().unwrap().close().expect("Connection::close");
-        forget(self);
+    /// Closes the connection, returning any error SQLite reports while doing so.
+    pub fn close(mut self) -> Result<(), DbError> {
+        match self.connection.take() {
+            Some(connection) => connection.close().map_err(|(_, e)| DbError::Sqlite(e)),
+            None => Ok(()),
+        }
     }
 }
 
 pub trait Save {
-    fn save(&mut self, connection: &mut Connection) -> Result<u32, rusqlite::Error>;
+    fn save(&mut self, connection: &mut Connection) -> Result<u32, DbError>;
 }
 
 impl Database {
-    pub fn save<T: Save>(&mut self, item: &mut T) {
-        let _ = item.save(self.connection.as_mut().unwrap());
+    pub fn save<T: Save>(&mut self, item: &mut T) -> Result<u32, DbError> {
+        item.save(self.connection_mut()?)
     }
 
-    pub fn save_all<T: Save>(&mut self, items: &mut Vec<T>) {
-        for item in items.iter_mut() {
-            self.save(item);
+    /// Saves every item, continuing past failures. Returns `DbError::Partial` listing the
+    /// position and error of each item that was not saved.
+    pub fn save_all<T: Save>(&mut self, items: &mut [T]) -> Result<(), DbError> {
+        let mut failed = Vec::new();
+        for (position, item) in items.iter_mut().enumerate() {
+            if let Err(e) = self.save(item) {
+                failed.push((position, e));
+            }
         }
-    }
-
-    pub fn select_image_by_path(&self, path: &str) -> Option<Image> {
-        let mut statement = self.connection.as_ref().unwrap()
-            .prepare("SELECT id, path, title FROM images WHERE path = ?1")
-            .expect("SELECT id, path, title FROM images WHERE path = ?1");
-        let rows = statement.query(&[&path]);
-
-        if rows.is_err() {
-            return None;
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(DbError::Partial { saved: items.len() - failed.len(), failed })
         }
-        let mut rows = rows.unwrap();
-
+    }
 
-        let row = rows.next();
-        if row.is_err() {
-            return None;
-        }
-        let row = row.unwrap();
-        if row.is_none() {
-            return None;
-        }
-        let row = row.unwrap();
+    pub fn select_image_by_path(&self, path: &str) -> Result<Option<Image>, DbError> {
+        let mut statement = self.connection()?
+            .prepare_cached("SELECT id, path, title, model_id, dimensions, vector, content_hash FROM images WHERE path = ?1")?;
+        let mut rows = statement.query([path])?;
+        let row = match rows.next()? {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let model_id: Option<String> = row.get(3)?;
+        let dimensions: Option<u32> = row.get(4)?;
+        let vector: Option<Vec<u8>> = row.get(5)?;
+        Ok(Some(Image {
+            id: row.get(0)?,
+            path: row.get(1)?,
+            title: row.get(2)?,
+            model_id: model_id.unwrap_or_default(),
+            dimensions: dimensions.unwrap_or(0),
+            semantic_vector: SemanticVec::from_blob(&vector.unwrap_or_default()),
+            content_hash: row.get(6)?,
+        }))
+    }
 
-        let image_id: Result<u32, rusqlite::Error> = row.get(0);
-        if image_id.is_err() {
-            return None;
-        }
-        let image_id = image_id.unwrap();
+    pub fn exists_image_by_path(&self, path: &str) -> Result<bool, DbError> {
+        let mut statement = self.connection()?.prepare_cached("SELECT id FROM images WHERE path = ?1")?;
+        let mut rows = statement.query([path])?;
+        Ok(rows.next()?.is_some())
+    }
 
-        let path: Result<String, rusqlite::Error> = row.get(1);
-        if path.is_err() {
-            return None;
-        }
-        let path = path.unwrap();
+    pub fn select_all_images(&self) -> Result<Vec<u32>, DbError> {
+        let mut statement = self.connection()?.prepare_cached("SELECT id FROM images")?;
+        let ids = statement.query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<u32>, rusqlite::Error>>()?;
+        Ok(ids)
+    }
+}
 
-        let title: Result<String, rusqlite::Error> = row.get(2);
-        if title.is_err() {
-            return None;
+impl Database {
+    pub fn get_setting(&self, key: &str) -> Result<Option<String>, DbError> {
+        let mut statement = self.connection()?
+            .prepare_cached("SELECT value FROM settings WHERE key = ?1")?;
+        let mut rows = statement.query([key])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(row.get(0)?)),
+            None => Ok(None),
         }
-        let title = title.unwrap();
-
+    }
 
-        let statement = self.connection.as_ref();
+    pub fn set_setting(&mut self, key: &str, value: &str) -> Result<(), DbError> {
+        self.connection()?.execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            [key, value],
+        )?;
+        Ok(())
+    }
 
-        if statement.is_none() {
-            return None;
-        }
-        let mut statement = statement.unwrap()
-            .prepare("SELECT id, image_id, value FROM semantic_vectors WHERE image_id = ?1")
-            .expect("SELECT id, image_id, value FROM semantic_vectors WHERE image_id = ?1");
+    /// Replaces the terms counted for an image, e.g. after it was analysed again.
+    pub fn set_image_terms(&mut self, image_id: u32, tokens: &[String]) -> Result<(), DbError> {
+        let tx = self.connection_mut()?.transaction()?;
+        uncount_terms(&tx, image_id)?;
+        count_terms(&tx, image_id, tokens)?;
+        Ok(tx.commit()?)
+    }
 
-        let elements: rusqlite::Result<Vec<SemanticVectorElement>> = statement.query_map(&[&image_id], |row| {
-            if row.get::<usize, u32>(0).is_err() {
-                return Err(row.get::<usize, u32>(0).err().unwrap());
-            }
-            if row.get::<usize, String>(1).is_err() {
-                return Err(row.get::<usize, String>(1).err().unwrap());
-            }
-            if row.get::<usize, String>(2).is_err() {
-                return Err(row.get::<usize, String>(2).err().unwrap());
-            }
+    /// Returns the number of counted documents and the counted terms.
+    pub fn select_term_stats(&self) -> Result<(u32, Vec<TermCount>), DbError> {
+        let documents = match self.get_setting("term_stats_documents")? {
+            Some(value) => value.parse().unwrap_or(0),
+            None => 0,
+        };
+        let mut statement = self.connection()?
+            .prepare_cached("SELECT term, document_count, term_count FROM term_stats")?;
+        let terms = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<Vec<TermCount>, rusqlite::Error>>()?;
+        Ok((documents, terms))
+    }
+}
 
-            let id: u32 = row.get(0).unwrap();
-            let image_id: u32 = row.get(1).unwrap();
-            let value: f32 = row.get(2).unwrap();
-            Ok(SemanticVectorElement {
-                id,
-                image_id,
-                value,
-            })
-        }).unwrap().collect();
-
-        if elements.is_err() {
-            return None;
+impl Database {
+    /// Returns the embedding model id and dimension this index was built with, if any.
+    pub fn index_model(&self) -> Result<Option<(String, usize)>, DbError> {
+        let model_id = self.get_setting("model_id")?;
+        let dimensions = self.get_setting("dimensions")?;
+        match (model_id, dimensions) {
+            (Some(model_id), Some(dimensions)) => Ok(Some((model_id, dimensions.parse().unwrap_or(0)))),
+            _ => Ok(None),
         }
+    }
 
-        let semantic_vec = SemanticVec(elements.unwrap());
+    /// Records the model for the index. Rows saved before model ids were tracked are assigned to it
+    /// when their vectors have its length; the others stay outside every model until re-embedded.
+    pub fn set_index_model(&mut self, model_id: &str, dimensions: usize) -> Result<(), DbError> {
+        self.set_setting("model_id", model_id)?;
+        self.set_setting("dimensions", &dimensions.to_string())?;
+        self.connection()?.execute(
+            "UPDATE images SET model_id = ?1, dimensions = ?2 WHERE model_id IS NULL AND length(vector) = ?2 * 4",
+            (model_id, dimensions as u32),
+        )?;
+        Ok(())
+    }
 
+    /// Lists `(id, path)` of images whose vectors come from a model other than `model_id`.
+    pub fn select_images_outside_model(&self, model_id: &str) -> Result<Vec<(u32, String)>, DbError> {
+        let mut statement = self.connection()?
+            .prepare_cached("SELECT id, path FROM images WHERE model_id IS NULL OR model_id != ?1")?;
+        let images = statement.query_map([model_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(u32, String)>, rusqlite::Error>>()?;
+        Ok(images)
+    }
 
-        Some(Image {
-            id: image_id,
-            path,
-            title,
-            semantic_vector: semantic_vec,
-        })
+    /// Replaces an image's vector and the model it belongs to.
+    pub fn update_image_vector(&mut self, image_id: u32, vector: &[f32], model_id: &str, dimensions: usize) -> Result<(), DbError> {
+        self.connection()?.execute(
+            "UPDATE images SET vector = ?1, model_id = ?2, dimensions = ?3 WHERE id = ?4",
+            (SemanticVec(vector.to_vec()).to_blob(), model_id, dimensions as u32, image_id),
+        )?;
+        Ok(())
     }
 
-    pub fn exists_image_by_path(&self, path: &str) -> Result<bool, rusqlite::Error> {
-        let mut statement = self.connection.as_ref().unwrap()
-            .prepare("SELECT id FROM images WHERE path = ?1")
-            .expect("SELECT id FROM images WHERE path = ?1");
-        let rows = statement.query(&[&path]);
-        if rows.is_err() {
-            return Err(rows.err().unwrap());
+    pub fn select_image_path(&self, image_id: u32) -> Result<Option<String>, DbError> {
+        let mut statement = self.connection()?
+            .prepare_cached("SELECT path FROM images WHERE id = ?1")?;
+        let mut rows = statement.query([image_id])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(row.get(0)?)),
+            None => Ok(None),
         }
-        let mut rows = rows.unwrap();
+    }
 
-        Ok(rows.next().unwrap().is_some())
+    pub fn delete_image(&mut self, image_id: u32) -> Result<(), DbError> {
+        let tx = self.connection_mut()?.transaction()?;
+        tx.execute("DELETE FROM image_tags WHERE image_id = ?1", [image_id])?;
+        tx.execute("DELETE FROM image_analyses WHERE image_id = ?1", [image_id])?;
+        tx.execute("DELETE FROM image_lines WHERE image_id = ?1", [image_id])?;
+        tx.execute("DELETE FROM image_regions WHERE image_id = ?1", [image_id])?;
+        remove_text(&tx, image_id)?;
+        uncount_terms(&tx, image_id)?;
+        tx.execute("DELETE FROM images WHERE id = ?1", [image_id])?;
+        Ok(tx.commit()?)
     }
 
-    pub fn select_all_images(&self) -> Vec<u32> {
-        let mut statement = self.connection.as_ref().unwrap()
-            .prepare("SELECT id FROM images")
-            .expect("");
-        let rows = statement.query(());
-        if rows.is_err() {
-            return vec![];
-        }
-        let mut rows = rows.unwrap();
+    pub fn select_analysis(&self, image_id: u32) -> Result<Option<ImageAnalysis>, DbError> {
+        let connection = self.connection()?;
+        let mut statement = connection.prepare_cached("SELECT caption, analyzed_at FROM image_analyses WHERE image_id = ?1")?;
+        let mut rows = statement.query([image_id])?;
+        let (caption, analyzed_at): (String, i64) = match rows.next()? {
+            Some(row) => (row.get(0)?, row.get(1)?),
+            None => return Ok(None),
+        };
 
+        let mut statement = connection.prepare_cached("SELECT name, confidence FROM image_tags WHERE image_id = ?1 ORDER BY confidence DESC")?;
+        let tags = statement.query_map([image_id], |row| Ok(Tag { name: row.get(0)?, confidence: row.get(1)? }))?
+            .collect::<Result<Vec<Tag>, rusqlite::Error>>()?;
+        let mut statement = connection.prepare_cached("SELECT text FROM image_lines WHERE image_id = ?1 ORDER BY line")?;
+        let lines = statement.query_map([image_id], |row| row.get(0))?
+            .collect::<Result<Vec<String>, rusqlite::Error>>()?;
+        let mut statement = connection.prepare_cached(
+            "SELECT kind, name, confidence, x, y, width, height FROM image_regions WHERE image_id = ?1 ORDER BY id",
+        )?;
+        let regions = statement.query_map([image_id], region_from_row)?
+            .collect::<Result<Vec<Option<Region>>, rusqlite::Error>>()?
+            .into_iter().flatten().collect();
+        Ok(Some(ImageAnalysis {
+            image_id,
+            caption,
+            tags,
+            lines,
+            regions,
+            analyzed_at,
+        }))
+    }
 
-        let mut result = vec![];
+    /// Loads `(id, path, vector)` for every image embedded with `model_id` in a single query.
+    pub fn select_vectors_by_model(&self, model_id: &str) -> Result<Vec<(u32, String, Vec<f32>)>, DbError> {
+        let mut statement = self.connection()?
+            .prepare_cached("SELECT id, path, vector FROM images WHERE model_id = ?1 AND vector IS NOT NULL")?;
+        let vectors = statement.query_map([model_id], |row| {
+            let vector: Vec<u8> = row.get(2)?;
+            Ok((row.get(0)?, row.get(1)?, SemanticVec::from_blob(&vector).0))
+        })?.collect::<Result<Vec<(u32, String, Vec<f32>)>, rusqlite::Error>>()?;
+        Ok(vectors)
+    }
+}
 
-        while let Ok(row) = rows.next() {
-            if row.is_none() {
-                break;
-            }
-            let row = row.unwrap();
+/// Adds one image's tokens to `term_stats` on `connection`, which may be an open transaction,
+/// and remembers them so `uncount_terms` can take them back out. Images without tokens are not counted.
+pub fn count_terms(connection: &Connection, image_id: u32, tokens: &[String]) -> Result<(), DbError> {
+    let mut counts: HashMap<&str, u32> = HashMap::new();
+    for token in tokens {
+        *counts.entry(token.as_str()).or_insert(0) += 1;
+    }
+    if counts.is_empty() {
+        return Ok(());
+    }
 
-            let image_id: Result<u32, rusqlite::Error> = row.get(0);
-            if image_id.is_err() {
-                break;
-            }
-            let image_id = image_id.unwrap();
+    let mut upsert = connection.prepare_cached(
+        "INSERT INTO term_stats (term, document_count, term_count) VALUES (?1, 1, ?2)
+         ON CONFLICT(term) DO UPDATE SET document_count = document_count + 1, term_count = term_count + ?2",
+    )?;
+    let mut remember = connection.prepare_cached(
+        "INSERT OR REPLACE INTO image_terms (image_id, term, count) VALUES (?1, ?2, ?3)",
+    )?;
+    for (term, count) in counts {
+        upsert.execute((term, count))?;
+        remember.execute((image_id, term, count))?;
+    }
+    connection.prepare_cached(
+        "INSERT INTO settings (key, value) VALUES ('term_stats_documents', '1')
+         ON CONFLICT(key) DO UPDATE SET value = CAST(value AS INTEGER) + 1",
+    )?.execute([])?;
+    Ok(())
+}
 
-            result.push(image_id)
-        }
-        result
+/// Takes the tokens `count_terms` recorded for an image back out of `term_stats`.
+pub fn uncount_terms(connection: &Connection, image_id: u32) -> Result<(), DbError> {
+    let removed = connection.prepare_cached(
+        "UPDATE term_stats SET document_count = document_count - 1, term_count = term_count - image_terms.count
+         FROM image_terms WHERE image_terms.image_id = ?1 AND image_terms.term = term_stats.term",
+    )?.execute([image_id])?;
+    if removed == 0 {
+        return Ok(());
     }
-}
\ No newline at end of file
+    connection.prepare_cached("DELETE FROM term_stats WHERE document_count <= 0")?.execute([])?;
+    connection.prepare_cached("DELETE FROM image_terms WHERE image_id = ?1")?.execute([image_id])?;
+    connection.prepare_cached(
+        "UPDATE settings SET value = MAX(CAST(value AS INTEGER) - 1, 0) WHERE key = 'term_stats_documents'",
+    )?.execute([])?;
+    Ok(())
+}
@@ -0,0 +1,39 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum DbError {
+    Sqlite(rusqlite::Error),
+    /// The database was written by a newer build with migrations this one does not know.
+    NewerSchema { found: u32, supported: u32 },
+    /// The connection was already closed.
+    Closed,
+    /// `save_all` could not save some items; holds each failed item's position and error.
+    Partial { saved: usize, failed: Vec<(usize, DbError)> },
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Sqlite(e) => write!(f, "database error: {}", e),
+            DbError::NewerSchema { found, supported } => write!(
+                f, "database schema version {} is newer than the supported version {}", found, supported
+            ),
+            DbError::Closed => write!(f, "database is closed"),
+            DbError::Partial { saved, failed } => {
+                write!(f, "{} of {} items could not be saved", failed.len(), saved + failed.len())?;
+                if let Some((position, e)) = failed.first() {
+                    write!(f, " (item {}: {})", position, e)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(e: rusqlite::Error) -> Self {
+        DbError::Sqlite(e)
+    }
+}
@@ -1,4 +1,5 @@
 use rusqlite::Connection;
+use crate::error::DbError;
 use crate::semantic_vector::{SemanticVec};
 
 #[derive(Debug)]
@@ -6,37 +7,34 @@ pub struct Image {
     pub id: u32,
     pub path: String,
     pub title: String,
+    pub model_id: String,
+    pub dimensions: u32,
     pub semantic_vector: SemanticVec,
+    /// SHA-256 of the file contents, `None` for images indexed before hashes were stored.
+    pub content_hash: Option<String>,
 }
 
 impl crate::database::Save for Image
 {
-    fn save(&mut self, connection: &mut Connection) -> Result<u32, rusqlite::Error> {
-        match connection
-            .execute(
-                "INSERT INTO images (path, title) VALUES (?1, ?2)",
-                &[&self.path, &self.title],
-            ) {
-            Ok(_) => {}
-            Err(e) => {
-                return Err(e);
-            }
-        }
+    fn save(&mut self, connection: &mut Connection) -> Result<u32, DbError> {
+        self.insert(connection)
+    }
+}
+
+impl Image {
+    /// Inserts the image on `connection`, which may be an open transaction, and sets its id.
+    pub fn insert(&mut self, connection: &Connection) -> Result<u32, DbError> {
+        connection.prepare_cached(
+            "INSERT INTO images (path, title, model_id, dimensions, vector, content_hash) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?.execute((&self.path, &self.title, &self.model_id, self.dimensions, self.semantic_vector.to_blob(), &self.content_hash))?;
 
         self.id = connection.last_insert_rowid() as u32;
 
         println!("Image save");
-        self.set_semantic_vector(self.semantic_vector.clone());
-        let _ = self.semantic_vector.save(connection);
-        Ok(connection.last_insert_rowid() as u32)
+        Ok(self.id)
     }
-}
 
-impl Image {
-    pub fn set_semantic_vector(&mut self, mut semantic_vector: SemanticVec) {
-        for element in semantic_vector.0.iter_mut() {
-            element.image_id = self.id;
-        }
+    pub fn set_semantic_vector(&mut self, semantic_vector: SemanticVec) {
         self.semantic_vector = semantic_vector;
     }
 
@@ -45,9 +43,21 @@ impl Image {
             id: 0,
             path,
             title,
+            model_id: String::new(),
+            dimensions: 0,
             semantic_vector: SemanticVec::new(),
+            content_hash: None,
         }
     }
+
+    pub fn set_content_hash(&mut self, content_hash: String) {
+        self.content_hash = Some(content_hash);
+    }
+
+    pub fn set_model(&mut self, model_id: String, dimensions: u32) {
+        self.model_id = model_id;
+        self.dimensions = dimensions;
+    }
 }
 
 
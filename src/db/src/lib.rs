@@ -1,3 +1,11 @@
+pub mod analysis;
+pub mod batch;
+pub mod cache;
 pub mod image;
 pub mod semantic_vector;
-pub mod database;
\ No newline at end of file
+pub mod database;
+pub mod error;
+pub mod migrations;
+pub mod text_search;
+pub mod usage;
+pub mod tags;
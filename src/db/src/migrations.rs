@@ -0,0 +1,324 @@
+use rusqlite::{Connection, Transaction};
+use crate::error::DbError;
+use crate::semantic_vector::SemanticVec;
+
+/// One schema change. `version` is the `PRAGMA user_version` the database has after it ran.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub apply: fn(&Transaction) -> Result<(), rusqlite::Error>,
+}
+
+/// Every schema change in order. Append new migrations at the end and never edit old ones.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "images, settings and term stats; upgrade databases created before migrations",
+        apply: initial_schema,
+    },
+    Migration {
+        version: 2,
+        description: "captions and tags from image analysis",
+        apply: image_analyses,
+    },
+    Migration {
+        version: 3,
+        description: "full-text index over captions and tags",
+        apply: image_text,
+    },
+    Migration {
+        version: 4,
+        description: "image content hashes and an analysis cache keyed by them",
+        apply: analysis_cache,
+    },
+    Migration {
+        version: 5,
+        description: "text read from images, stored per line and added to the full-text index",
+        apply: image_lines,
+    },
+    Migration {
+        version: 6,
+        description: "detected objects and captioned regions with their bounding boxes",
+        apply: image_regions,
+    },
+    Migration {
+        version: 7,
+        description: "vision service requests per indexing run and per day",
+        apply: api_usage,
+    },
+    Migration {
+        version: 8,
+        description: "terms counted per image, so deleted and re-analysed images leave the term stats",
+        apply: image_terms,
+    },
+    Migration {
+        version: 9,
+        description: "vision service requests move to the shared usage database; drop the empty usage tables",
+        apply: drop_empty_api_usage,
+    },
+];
+
+/// Schema of the usage database shared by all libraries, see `Database::open_usage`.
+pub const USAGE_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "vision service requests per indexing run and per day",
+        apply: api_usage,
+    },
+];
+
+pub fn latest_version() -> u32 {
+    latest_version_of(MIGRATIONS)
+}
+
+pub fn latest_version_of(migrations: &[Migration]) -> u32 {
+    migrations.last().map(|migration| migration.version).unwrap_or(0)
+}
+
+pub fn schema_version(connection: &Connection) -> Result<u32, DbError> {
+    Ok(connection.pragma_query_value(None, "user_version", |row| row.get(0))?)
+}
+
+/// Brings a library database up to `latest_version()`, one transaction per migration.
+pub fn migrate(connection: &mut Connection) -> Result<(), DbError> {
+    migrate_with(connection, MIGRATIONS)
+}
+
+/// Brings the database up to the last of `migrations`, one transaction per migration.
+pub fn migrate_with(connection: &mut Connection, migrations: &[Migration]) -> Result<(), DbError> {
+    let current = schema_version(connection)?;
+    let supported = latest_version_of(migrations);
+    if current > supported {
+        return Err(DbError::NewerSchema { found: current, supported });
+    }
+
+    for migration in migrations.iter().filter(|migration| migration.version > current) {
+        println!("Applying migration {}: {}", migration.version, migration.description);
+        let tx = connection.transaction()?;
+        (migration.apply)(&tx)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn initial_schema(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS images (
+            id INTEGER PRIMARY KEY,
+            path TEXT NOT NULL UNIQUE,
+            title TEXT NOT NULL
+        )",
+        [],
+    )?;
+    add_column_if_missing(tx, "images", "model_id", "TEXT")?;
+    add_column_if_missing(tx, "images", "dimensions", "INTEGER")?;
+    add_column_if_missing(tx, "images", "vector", "BLOB")?;
+    tx.execute("CREATE INDEX IF NOT EXISTS index_images_on_path ON images (path)", [])?;
+    migrate_row_vectors(tx)?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        [],
+    )?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS term_stats (
+            term TEXT PRIMARY KEY,
+            document_count INTEGER NOT NULL,
+            term_count INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn image_analyses(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "CREATE TABLE image_analyses (
+            image_id INTEGER PRIMARY KEY REFERENCES images(id),
+            caption TEXT NOT NULL,
+            analyzed_at INTEGER NOT NULL
+        );
+        CREATE TABLE image_tags (
+            id INTEGER PRIMARY KEY,
+            image_id INTEGER NOT NULL REFERENCES images(id),
+            name TEXT NOT NULL,
+            confidence REAL NOT NULL
+        );
+        CREATE INDEX index_image_tags_on_image_id ON image_tags (image_id);
+        CREATE INDEX index_image_tags_on_name ON image_tags (name);",
+    )
+}
+
+fn image_text(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "CREATE VIRTUAL TABLE image_text USING fts5(caption, tags, tokenize = 'porter unicode61');
+        INSERT INTO image_text (rowid, caption, tags)
+            SELECT image_id, caption,
+                COALESCE((SELECT group_concat(name, ' ') FROM image_tags WHERE image_tags.image_id = image_analyses.image_id), '')
+            FROM image_analyses;",
+    )
+}
+
+fn analysis_cache(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    add_column_if_missing(tx, "images", "content_hash", "TEXT")?;
+    tx.execute_batch(
+        "CREATE INDEX index_images_on_content_hash ON images (content_hash);
+        CREATE TABLE analysis_cache (
+            content_hash TEXT PRIMARY KEY,
+            caption TEXT NOT NULL,
+            analyzed_at INTEGER NOT NULL
+        );
+        CREATE TABLE analysis_cache_tags (
+            content_hash TEXT NOT NULL REFERENCES analysis_cache(content_hash),
+            name TEXT NOT NULL,
+            confidence REAL NOT NULL,
+            PRIMARY KEY (content_hash, name)
+        );",
+    )
+}
+
+fn image_lines(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    // FTS5 tables cannot gain columns, so `image_text` is rebuilt with a `text` column.
+    tx.execute_batch(
+        "CREATE TABLE image_lines (
+            image_id INTEGER NOT NULL REFERENCES images(id),
+            line INTEGER NOT NULL,
+            text TEXT NOT NULL,
+            PRIMARY KEY (image_id, line)
+        );
+        CREATE TABLE analysis_cache_lines (
+            content_hash TEXT NOT NULL REFERENCES analysis_cache(content_hash),
+            line INTEGER NOT NULL,
+            text TEXT NOT NULL,
+            PRIMARY KEY (content_hash, line)
+        );
+        DROP TABLE image_text;
+        CREATE VIRTUAL TABLE image_text USING fts5(caption, tags, text, tokenize = 'porter unicode61');
+        INSERT INTO image_text (rowid, caption, tags, text)
+            SELECT image_id, caption,
+                COALESCE((SELECT group_concat(name, ' ') FROM image_tags WHERE image_tags.image_id = image_analyses.image_id), ''),
+                ''
+            FROM image_analyses;",
+    )
+}
+
+fn image_regions(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "CREATE TABLE image_regions (
+            id INTEGER PRIMARY KEY,
+            image_id INTEGER NOT NULL REFERENCES images(id),
+            kind TEXT NOT NULL,
+            name TEXT NOT NULL,
+            confidence REAL NOT NULL,
+            x REAL NOT NULL,
+            y REAL NOT NULL,
+            width REAL NOT NULL,
+            height REAL NOT NULL
+        );
+        CREATE INDEX index_image_regions_on_image_id ON image_regions (image_id);
+        CREATE TABLE analysis_cache_regions (
+            id INTEGER PRIMARY KEY,
+            content_hash TEXT NOT NULL REFERENCES analysis_cache(content_hash),
+            kind TEXT NOT NULL,
+            name TEXT NOT NULL,
+            confidence REAL NOT NULL,
+            x REAL NOT NULL,
+            y REAL NOT NULL,
+            width REAL NOT NULL,
+            height REAL NOT NULL
+        );
+        CREATE INDEX index_analysis_cache_regions_on_content_hash ON analysis_cache_regions (content_hash);",
+    )
+}
+
+fn api_usage(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "CREATE TABLE api_usage_runs (
+            id INTEGER PRIMARY KEY,
+            started_at INTEGER NOT NULL,
+            requests INTEGER NOT NULL,
+            successes INTEGER NOT NULL,
+            failures INTEGER NOT NULL
+        );
+        CREATE TABLE api_usage_days (
+            day TEXT PRIMARY KEY,
+            requests INTEGER NOT NULL,
+            successes INTEGER NOT NULL,
+            failures INTEGER NOT NULL
+        );",
+    )
+}
+
+/// Usage a library counted before it was shared is kept until `Database::drop_usage_tables`
+/// runs after moving it; new libraries end up without the tables.
+fn drop_empty_api_usage(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    let used: bool = tx.query_row("SELECT EXISTS (SELECT 1 FROM api_usage_days)", [], |row| row.get(0))?;
+    if !used {
+        tx.execute_batch("DROP TABLE api_usage_runs; DROP TABLE api_usage_days;")?;
+    }
+    Ok(())
+}
+
+/// Images counted before this migration have no rows here and stay in the term stats.
+fn image_terms(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "CREATE TABLE image_terms (
+            image_id INTEGER NOT NULL REFERENCES images(id),
+            term TEXT NOT NULL,
+            count INTEGER NOT NULL,
+            PRIMARY KEY (image_id, term)
+        );",
+    )
+}
+
+fn add_column_if_missing(connection: &Connection, table: &str, column: &str, definition: &str) -> Result<(), rusqlite::Error> {
+    let mut statement = connection.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = statement.query_map([], |row| row.get::<usize, String>(1))?
+        .collect::<Result<Vec<String>, rusqlite::Error>>()?;
+    if !columns.iter().any(|name| name == column) {
+        connection.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
+    Ok(())
+}
+
+/// Packs vectors from the old one-row-per-float `semantic_vectors` table into
+/// `images.vector` and drops the table.
+fn migrate_row_vectors(connection: &Connection) -> Result<(), rusqlite::Error> {
+    let exists: bool = connection.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'semantic_vectors')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        return Ok(());
+    }
+    println!("Migrating semantic vectors to BLOBs");
+
+    {
+        let mut select = connection.prepare("SELECT image_id, value FROM semantic_vectors ORDER BY image_id, id")?;
+        let mut update = connection.prepare("UPDATE images SET vector = ?1, dimensions = ?2 WHERE id = ?3")?;
+        let mut rows = select.query([])?;
+        let mut current: Option<u32> = None;
+        let mut values = Vec::new();
+        while let Some(row) = rows.next()? {
+            let image_id: u32 = row.get(0)?;
+            let value: f32 = row.get::<usize, f64>(1)? as f32;
+            if current != Some(image_id) {
+                if let Some(id) = current {
+                    update.execute((SemanticVec(values.clone()).to_blob(), values.len() as u32, id))?;
+                    values.clear();
+                }
+                current = Some(image_id);
+            }
+            values.push(value);
+        }
+        if let Some(id) = current {
+            update.execute((SemanticVec(values.clone()).to_blob(), values.len() as u32, id))?;
+        }
+    }
+    connection.execute("DROP TABLE semantic_vectors", [])?;
+    Ok(())
+}
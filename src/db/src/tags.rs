@@ -0,0 +1,42 @@
+use rusqlite::params_from_iter;
+use rusqlite::types::Value;
+use crate::database::Database;
+use crate::error::DbError;
+
+impl Database {
+    /// Returns up to `limit` `(tag, number of images)` pairs, most common first. Only tags
+    /// with at least `min_confidence` are counted.
+    pub fn select_tag_counts(&self, min_confidence: f64, limit: usize) -> Result<Vec<(String, u32)>, DbError> {
+        let mut statement = self.connection()?.prepare(
+            "SELECT name, COUNT(DISTINCT image_id) AS images FROM image_tags
+             WHERE confidence >= ?1
+             GROUP BY name ORDER BY images DESC, name LIMIT ?2",
+        )?;
+        let counts = statement.query_map((min_confidence, limit as i64), |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(String, u32)>, rusqlite::Error>>()?;
+        Ok(counts)
+    }
+
+    /// Ids of images carrying every tag in `tags` with at least `min_confidence`.
+    pub fn select_images_with_tags(&self, tags: &[String], min_confidence: f64) -> Result<Vec<u32>, DbError> {
+        if tags.is_empty() {
+            return Ok(Vec::new());
+        }
+        let placeholders = vec!["?"; tags.len()].join(", ");
+        let sql = format!(
+            "SELECT image_id FROM image_tags
+             WHERE confidence >= ? AND name IN ({})
+             GROUP BY image_id HAVING COUNT(DISTINCT name) = ?
+             ORDER BY image_id",
+            placeholders
+        );
+        let mut params = vec![Value::Real(min_confidence)];
+        params.extend(tags.iter().map(|tag| Value::Text(tag.clone())));
+        params.push(Value::Integer(tags.len() as i64));
+
+        let mut statement = self.connection()?.prepare(&sql)?;
+        let ids = statement.query_map(params_from_iter(params), |row| row.get(0))?
+            .collect::<Result<Vec<u32>, rusqlite::Error>>()?;
+        Ok(ids)
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use rusqlite::Connection;
+use crate::analysis::{Region, RegionKind};
+use crate::database::Database;
+use crate::error::DbError;
+
+/// Replaces the searchable caption, tags and recognised text of an image in the `image_text` FTS5 table.
+pub fn index_text(connection: &Connection, image_id: u32, caption: &str, tags: &[String], lines: &[String]) -> Result<(), DbError> {
+    remove_text(connection, image_id)?;
+    connection.prepare_cached("INSERT INTO image_text (rowid, caption, tags, text) VALUES (?1, ?2, ?3, ?4)")?
+        .execute((image_id, caption, tags.join(" "), lines.join("\n")))?;
+    Ok(())
+}
+
+pub fn remove_text(connection: &Connection, image_id: u32) -> Result<(), DbError> {
+    connection.prepare_cached("DELETE FROM image_text WHERE rowid = ?1")?.execute([image_id])?;
+    Ok(())
+}
+
+/// Turns free text into an FTS5 query matching any of its words, so punctuation and
+/// FTS operators typed by the user cannot break the query.
+pub fn match_query(text: &str) -> Option<String> {
+    let terms: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{}\"", word.to_lowercase()))
+        .collect();
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" OR "))
+    }
+}
+
+impl Database {
+    /// BM25-ranked keyword search over captions, tags and text read from the images. Returns up to `limit`
+    /// `(image id, score)` pairs, best first; higher scores are better.
+    pub fn keyword_search(&self, text: &str, limit: usize) -> Result<Vec<(u32, f32)>, DbError> {
+        let query = match match_query(text) {
+            Some(query) => query,
+            None => return Ok(Vec::new()),
+        };
+        let mut statement = self.connection()?.prepare_cached(
+            "SELECT rowid, bm25(image_text) FROM image_text WHERE image_text MATCH ?1
+             ORDER BY bm25(image_text) LIMIT ?2",
+        )?;
+        // bm25() is lower for better matches, so it is negated to rank like similarity.
+        let hits = statement.query_map((query, limit as i64), |row| {
+            Ok((row.get(0)?, -row.get::<usize, f64>(1)? as f32))
+        })?.collect::<Result<Vec<(u32, f32)>, rusqlite::Error>>()?;
+        Ok(hits)
+    }
+}
+
+/// Rank offset for reciprocal rank fusion; 60 is the usual choice and damps the
+/// influence of the very top ranks.
+const RRF_K: f32 = 60.0;
+
+/// Merges ranked `(id, score)` lists, scoring each id by the sum of `1 / (RRF_K + rank)`.
+/// Ranks are used instead of raw scores because BM25 and cosine values are not comparable.
+pub fn fuse_ranks(lists: &[Vec<(u32, f32)>], k: usize) -> Vec<(u32, f32)> {
+    let mut scores: HashMap<u32, f32> = HashMap::new();
+    for list in lists {
+        for (rank, (id, _)) in list.iter().enumerate() {
+            *scores.entry(*id).or_insert(0.0) += 1.0 / (RRF_K + rank as f32 + 1.0);
+        }
+    }
+    let mut fused: Vec<(u32, f32)> = scores.into_iter().collect();
+    fused.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal).then(a.0.cmp(&b.0)));
+    fused.truncate(k);
+    fused
+}
+
+/// Lowercased words of a query, split the way `match_query` splits them.
+fn query_words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+/// The lines of `lines` containing any word of `text`, for showing why an image matched.
+pub fn matching_lines(lines: &[String], text: &str) -> Vec<String> {
+    let words = query_words(text);
+    lines.iter()
+        .filter(|line| {
+            let line = line.to_lowercase();
+            words.iter().any(|word| line.contains(word.as_str()))
+        })
+        .cloned()
+        .collect()
+}
+
+/// The detected objects named by a word of `text`, for drawing boxes around what matched.
+pub fn matching_objects(regions: &[Region], text: &str) -> Vec<Region> {
+    let words = query_words(text);
+    regions.iter()
+        .filter(|region| region.kind == RegionKind::Object)
+        .filter(|region| {
+            let name = region.name.to_lowercase();
+            query_words(&name).iter().any(|part| words.contains(part))
+        })
+        .cloned()
+        .collect()
+}
@@ -0,0 +1,120 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::database::Database;
+use crate::error::DbError;
+
+/// Requests sent to the vision service, as recorded per indexing run and per UTC day.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ApiUsage {
+    pub requests: u64,
+    pub successes: u64,
+    pub failures: u64,
+}
+
+impl ApiUsage {
+    pub fn is_empty(&self) -> bool {
+        self.requests == 0 && self.successes == 0 && self.failures == 0
+    }
+}
+
+/// `YYYY-MM-DD` of a Unix timestamp, in UTC.
+pub fn day_of(unix_seconds: i64) -> String {
+    // Days since 1970-01-01 to a civil date, after Howard Hinnant's `civil_from_days`.
+    let days = unix_seconds.div_euclid(86_400) + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Today's `YYYY-MM-DD` in UTC.
+pub fn today() -> String {
+    day_of(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0))
+}
+
+impl Database {
+    /// Starts counting a new indexing run and returns its id.
+    pub fn start_usage_run(&mut self) -> Result<u32, DbError> {
+        let started_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+        let connection = self.connection()?;
+        connection.prepare_cached(
+            "INSERT INTO api_usage_runs (started_at, requests, successes, failures) VALUES (?1, 0, 0, 0)",
+        )?.execute([started_at])?;
+        Ok(connection.last_insert_rowid() as u32)
+    }
+
+    /// Adds `usage` to the run and to the totals of `day`.
+    pub fn add_usage(&mut self, run_id: u32, day: &str, usage: &ApiUsage) -> Result<(), DbError> {
+        let tx = self.connection_mut()?.transaction()?;
+        tx.prepare_cached(
+            "UPDATE api_usage_runs SET requests = requests + ?1, successes = successes + ?2, failures = failures + ?3
+             WHERE id = ?4",
+        )?.execute((usage.requests, usage.successes, usage.failures, run_id))?;
+        tx.prepare_cached(
+            "INSERT INTO api_usage_days (day, requests, successes, failures) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (day) DO UPDATE SET requests = requests + ?2, successes = successes + ?3, failures = failures + ?4",
+        )?.execute((day, usage.requests, usage.successes, usage.failures))?;
+        Ok(tx.commit()?)
+    }
+
+    /// Every day's totals, oldest first.
+    pub fn select_usage_days(&self) -> Result<Vec<(String, ApiUsage)>, DbError> {
+        let mut statement = self.connection()?
+            .prepare_cached("SELECT day, requests, successes, failures FROM api_usage_days ORDER BY day")?;
+        let days = statement.query_map([], |row| {
+            Ok((row.get(0)?, ApiUsage { requests: row.get(1)?, successes: row.get(2)?, failures: row.get(3)? }))
+        })?.collect::<Result<Vec<(String, ApiUsage)>, rusqlite::Error>>()?;
+        Ok(days)
+    }
+
+    /// Whether this library still has usage counted before usage was shared.
+    pub fn has_usage_tables(&self) -> Result<bool, DbError> {
+        Ok(self.connection()?.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'api_usage_days')",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Drops a library's usage tables once their totals are in the shared usage database.
+    pub fn drop_usage_tables(&mut self) -> Result<(), DbError> {
+        self.connection()?.execute_batch("DROP TABLE IF EXISTS api_usage_runs; DROP TABLE IF EXISTS api_usage_days;")?;
+        Ok(())
+    }
+
+    /// Adds `usage` to the totals of `day` without counting it for a run.
+    pub fn add_day_usage(&mut self, day: &str, usage: &ApiUsage) -> Result<(), DbError> {
+        self.connection()?.prepare_cached(
+            "INSERT INTO api_usage_days (day, requests, successes, failures) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (day) DO UPDATE SET requests = requests + ?2, successes = successes + ?3, failures = failures + ?4",
+        )?.execute((day, usage.requests, usage.successes, usage.failures))?;
+        Ok(())
+    }
+
+    /// The most recent run, `None` before the first one.
+    pub fn select_last_run_usage(&self) -> Result<Option<ApiUsage>, DbError> {
+        let mut statement = self.connection()?.prepare_cached(
+            "SELECT requests, successes, failures FROM api_usage_runs ORDER BY id DESC LIMIT 1",
+        )?;
+        let mut rows = statement.query([])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(ApiUsage { requests: row.get(0)?, successes: row.get(1)?, failures: row.get(2)? })),
+            None => Ok(None),
+        }
+    }
+
+    /// Totals of the days starting with `prefix`: a whole day (`2024-05-17`) or month (`2024-05`).
+    pub fn select_usage(&self, prefix: &str) -> Result<ApiUsage, DbError> {
+        let mut statement = self.connection()?.prepare_cached(
+            "SELECT COALESCE(SUM(requests), 0), COALESCE(SUM(successes), 0), COALESCE(SUM(failures), 0)
+             FROM api_usage_days WHERE substr(day, 1, length(?1)) = ?1",
+        )?;
+        Ok(statement.query_row([prefix], |row| {
+            Ok(ApiUsage { requests: row.get(0)?, successes: row.get(1)?, failures: row.get(2)? })
+        })?)
+    }
+}
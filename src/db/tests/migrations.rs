@@ -0,0 +1,171 @@
+use rusqlite::Connection;
+use db::database::Database;
+use db::error::DbError;
+use db::migrations::{latest_version, latest_version_of, migrate, schema_version, MIGRATIONS, USAGE_MIGRATIONS};
+
+fn temp_db(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("db-migrations-{}-{}.db", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path.to_str().unwrap().to_string()
+}
+
+fn tables(connection: &Connection) -> Vec<String> {
+    let mut statement = connection.prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name").unwrap();
+    let names = statement.query_map([], |row| row.get(0)).unwrap().collect::<Result<Vec<String>, _>>().unwrap();
+    names
+}
+
+/// Applies the migrations up to and including `version`, as an older build would have.
+fn migrate_to(connection: &mut Connection, version: u32) {
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version <= version) {
+        let tx = connection.transaction().unwrap();
+        (migration.apply)(&tx).unwrap();
+        tx.pragma_update(None, "user_version", migration.version).unwrap();
+        tx.commit().unwrap();
+    }
+}
+
+#[test]
+fn versions_are_consecutive() {
+    for (position, migration) in MIGRATIONS.iter().enumerate() {
+        assert_eq!(migration.version, position as u32 + 1);
+    }
+}
+
+#[test]
+fn a_new_database_gets_every_migration() {
+    let db = Database::in_memory().unwrap();
+    let connection = db.connection().unwrap();
+    assert_eq!(schema_version(connection).unwrap(), latest_version());
+    let tables = tables(connection);
+    for table in ["images", "settings", "term_stats", "image_analyses", "image_tags", "image_text",
+                  "analysis_cache", "image_lines", "image_regions", "image_terms"] {
+        assert!(tables.contains(&table.to_string()), "{} missing from {:?}", table, tables);
+    }
+    // Requests are counted in the shared usage database.
+    assert!(!tables.iter().any(|table| table.starts_with("api_usage")), "{:?}", tables);
+}
+
+#[test]
+fn the_usage_database_has_only_usage_tables() {
+    for (position, migration) in USAGE_MIGRATIONS.iter().enumerate() {
+        assert_eq!(migration.version, position as u32 + 1);
+    }
+    let db = Database::usage_in_memory().unwrap();
+    let connection = db.connection().unwrap();
+    assert_eq!(schema_version(connection).unwrap(), latest_version_of(USAGE_MIGRATIONS));
+    assert_eq!(tables(connection), vec!["api_usage_days", "api_usage_runs"]);
+}
+
+#[test]
+fn libraries_keep_usage_counted_before_it_was_shared() {
+    let (used, unused) = (temp_db("usage-v8"), temp_db("no-usage-v8"));
+    for path in [&used, &unused] {
+        migrate_to(&mut Connection::open(path).unwrap(), 8);
+    }
+    Connection::open(&used).unwrap().execute_batch(
+        "INSERT INTO api_usage_days (day, requests, successes, failures) VALUES ('2024-05-31', 3, 3, 0);",
+    ).unwrap();
+
+    let used = Database::open(&used).unwrap();
+    let unused = Database::open(&unused).unwrap();
+
+    assert!(used.has_usage_tables().unwrap());
+    assert_eq!(used.select_usage_days().unwrap().len(), 1);
+    assert!(!unused.has_usage_tables().unwrap());
+}
+
+#[test]
+fn migrating_twice_changes_nothing() {
+    let path = temp_db("twice");
+    Database::open(&path).unwrap().close().unwrap();
+    let mut connection = Connection::open(&path).unwrap();
+    let before = tables(&connection);
+    migrate(&mut connection).unwrap();
+    assert_eq!(schema_version(&connection).unwrap(), latest_version());
+    assert_eq!(tables(&connection), before);
+}
+
+#[test]
+fn databases_from_before_migrations_keep_their_vectors() {
+    let path = temp_db("legacy");
+    {
+        let connection = Connection::open(&path).unwrap();
+        connection.execute_batch(
+            "CREATE TABLE images (id INTEGER PRIMARY KEY, path TEXT NOT NULL UNIQUE, title TEXT NOT NULL);
+            CREATE TABLE semantic_vectors (id INTEGER PRIMARY KEY, image_id INTEGER, value REAL NOT NULL);
+            INSERT INTO images (id, path, title) VALUES (1, 'a.jpg', 'a.jpg'), (2, 'b.jpg', 'b.jpg');
+            INSERT INTO semantic_vectors (image_id, value) VALUES (1, 0.5), (1, -1.0), (2, 0.25), (2, 2.0);",
+        ).unwrap();
+    }
+
+    let db = Database::open(&path).unwrap();
+    assert_eq!(schema_version(db.connection().unwrap()).unwrap(), latest_version());
+    assert!(!tables(db.connection().unwrap()).contains(&"semantic_vectors".to_string()));
+    let a = db.select_image_by_path("a.jpg").unwrap().unwrap();
+    let b = db.select_image_by_path("b.jpg").unwrap().unwrap();
+    assert_eq!(a.semantic_vector.0, vec![0.5, -1.0]);
+    assert_eq!(b.semantic_vector.0, vec![0.25, 2.0]);
+    assert_eq!(a.dimensions, 2);
+}
+
+#[test]
+fn legacy_vectors_are_only_claimed_by_a_model_of_their_length() {
+    let path = temp_db("legacy-model");
+    {
+        let connection = Connection::open(&path).unwrap();
+        connection.execute_batch(
+            "CREATE TABLE images (id INTEGER PRIMARY KEY, path TEXT NOT NULL UNIQUE, title TEXT NOT NULL);
+            CREATE TABLE semantic_vectors (id INTEGER PRIMARY KEY, image_id INTEGER, value REAL NOT NULL);
+            INSERT INTO images (id, path, title) VALUES (1, 'a.jpg', 'a.jpg'), (2, 'b.jpg', 'b.jpg');
+            INSERT INTO semantic_vectors (image_id, value) VALUES (1, 0.5), (1, -1.0), (2, 0.25), (2, 2.0), (2, 1.0);",
+        ).unwrap();
+    }
+
+    let mut db = Database::open(&path).unwrap();
+    db.set_index_model("two-dims", 2).unwrap();
+
+    let outside = db.select_images_outside_model("two-dims").unwrap();
+    assert_eq!(outside, vec![(2, "b.jpg".to_string())]);
+    let b = db.select_image_by_path("b.jpg").unwrap().unwrap();
+    assert_eq!(b.semantic_vector.0, vec![0.25, 2.0, 1.0]);
+    assert_eq!(b.dimensions, 3);
+}
+
+#[test]
+fn captions_indexed_by_an_older_schema_stay_searchable() {
+    let path = temp_db("v3");
+    {
+        let mut connection = Connection::open(&path).unwrap();
+        migrate_to(&mut connection, 3);
+        connection.execute_batch(
+            "INSERT INTO images (id, path, title) VALUES (1, 'dog.jpg', 'dog.jpg');
+            INSERT INTO image_analyses (image_id, caption, analyzed_at) VALUES (1, 'a dog on a beach', 0);
+            INSERT INTO image_tags (image_id, name, confidence) VALUES (1, 'sand', 0.9);
+            INSERT INTO image_text (rowid, caption, tags) VALUES (1, 'a dog on a beach', 'sand');",
+        ).unwrap();
+    }
+
+    let db = Database::open(&path).unwrap();
+    assert_eq!(db.keyword_search("dog", 10).unwrap().len(), 1);
+    assert_eq!(db.keyword_search("sand", 10).unwrap().len(), 1);
+    let analysis = db.select_analysis(1).unwrap().unwrap();
+    assert_eq!(analysis.caption, "a dog on a beach");
+    assert!(analysis.lines.is_empty() && analysis.regions.is_empty());
+}
+
+#[test]
+fn newer_schemas_are_refused() {
+    let path = temp_db("newer");
+    {
+        let connection = Connection::open(&path).unwrap();
+        connection.pragma_update(None, "user_version", latest_version() + 1).unwrap();
+    }
+    match Database::open(&path) {
+        Err(DbError::NewerSchema { found, supported }) => {
+            assert_eq!(found, latest_version() + 1);
+            assert_eq!(supported, latest_version());
+        }
+        other => panic!("expected NewerSchema, got {:?}", other),
+    }
+}
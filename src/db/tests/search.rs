@@ -0,0 +1,154 @@
+use std::sync::{Arc, Mutex};
+use db::analysis::{ImageAnalysis, Tag};
+use db::batch::{BatchWriter, PendingImage};
+use db::database::Database;
+use db::image::Image;
+use db::text_search::fuse_ranks;
+
+fn pending(path: &str, caption: &str, tags: &[(&str, f64)]) -> PendingImage {
+    let tags = tags.iter().map(|(name, confidence)| Tag { name: name.to_string(), confidence: *confidence }).collect();
+    PendingImage {
+        image: Image::new(path.to_string(), path.to_string()),
+        analysis: ImageAnalysis::new(0, caption.to_string(), tags),
+        tokens: Vec::new(),
+    }
+}
+
+/// Indexes the images and returns their ids in order.
+fn index(db: &mut Database, images: Vec<PendingImage>) -> Vec<u32> {
+    let mut batch = images;
+    db.save_batch(&mut batch).unwrap();
+    batch.iter().map(|pending| pending.image.id).collect()
+}
+
+fn ids(hits: &[(u32, f32)]) -> Vec<u32> {
+    hits.iter().map(|(id, _)| *id).collect()
+}
+
+#[test]
+fn keyword_search_ranks_better_matches_first() {
+    let mut db = Database::in_memory().unwrap();
+    let ids_ = index(&mut db, vec![
+        pending("beach.jpg", "people on a beach", &[("sand", 0.9)]),
+        pending("dog.jpg", "a dog running after a dog", &[("dog", 0.95), ("grass", 0.8)]),
+        pending("cat.jpg", "a cat next to a dog bowl", &[("cat", 0.9)]),
+    ]);
+
+    let hits = db.keyword_search("dog", 10).unwrap();
+    assert_eq!(ids(&hits), vec![ids_[1], ids_[2]]);
+    assert!(hits[0].1 > hits[1].1);
+    assert!(db.keyword_search("giraffe", 10).unwrap().is_empty());
+}
+
+#[test]
+fn keyword_search_ignores_query_syntax() {
+    let mut db = Database::in_memory().unwrap();
+    index(&mut db, vec![pending("dog.jpg", "a dog", &[])]);
+    assert_eq!(db.keyword_search("dog\" OR NEAR(", 10).unwrap().len(), 1);
+    assert!(db.keyword_search("\"*()", 10).unwrap().is_empty());
+}
+
+#[test]
+fn deleted_images_leave_the_keyword_index() {
+    let mut db = Database::in_memory().unwrap();
+    let ids_ = index(&mut db, vec![pending("dog.jpg", "a dog", &[])]);
+    db.delete_image(ids_[0]).unwrap();
+    assert!(db.keyword_search("dog", 10).unwrap().is_empty());
+}
+
+#[test]
+fn fused_ranks_favour_ids_found_by_both_lists() {
+    let semantic = vec![(1, 0.9), (2, 0.8), (3, 0.7)];
+    let keyword = vec![(3, 12.0), (4, 9.0)];
+
+    let fused = fuse_ranks(&[semantic, keyword], 10);
+
+    assert_eq!(ids(&fused), vec![3, 1, 2, 4]);
+    assert!(fused.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+}
+
+#[test]
+fn fused_ranks_break_ties_by_id_and_keep_k() {
+    let fused = fuse_ranks(&[vec![(7, 1.0)], vec![(5, 1.0)], vec![(6, 1.0)]], 2);
+    assert_eq!(ids(&fused), vec![5, 6]);
+    assert!(fuse_ranks(&[Vec::new(), Vec::new()], 10).is_empty());
+}
+
+#[test]
+fn hybrid_ranking_combines_keyword_and_vector_hits() {
+    let mut db = Database::in_memory().unwrap();
+    let ids_ = index(&mut db, vec![
+        pending("puppy.jpg", "a puppy on a sofa", &[]),
+        pending("dog.jpg", "a dog on a sofa", &[]),
+        pending("sofa.jpg", "an empty sofa", &[]),
+    ]);
+    // The vector side finds the puppy first; only the keyword side knows the word "dog".
+    let semantic = vec![(ids_[0], 0.9), (ids_[1], 0.85), (ids_[2], 0.2)];
+    let keyword = db.keyword_search("dog", 10).unwrap();
+
+    let fused = fuse_ranks(&[semantic, keyword], 10);
+
+    assert_eq!(ids(&fused), vec![ids_[1], ids_[0], ids_[2]]);
+}
+
+#[test]
+fn tags_are_counted_and_filtered_by_confidence() {
+    let mut db = Database::in_memory().unwrap();
+    let ids_ = index(&mut db, vec![
+        pending("a.jpg", "", &[("dog", 0.9), ("grass", 0.4)]),
+        pending("b.jpg", "", &[("dog", 0.8), ("grass", 0.9)]),
+        pending("c.jpg", "", &[("cat", 0.9)]),
+    ]);
+
+    assert_eq!(db.select_tag_counts(0.5, 10).unwrap(), vec![
+        ("dog".to_string(), 2),
+        ("cat".to_string(), 1),
+        ("grass".to_string(), 1),
+    ]);
+    assert_eq!(db.select_images_with_tags(&["dog".to_string(), "grass".to_string()], 0.5).unwrap(), vec![ids_[1]]);
+    assert_eq!(db.select_images_with_tags(&["dog".to_string()], 0.5).unwrap(), vec![ids_[0], ids_[1]]);
+}
+
+#[test]
+fn batch_writer_commits_everything_it_was_sent() {
+    let db = Arc::new(Mutex::new(Some(Database::in_memory().unwrap())));
+    let saved = Arc::new(Mutex::new(Vec::new()));
+    let writer = {
+        let saved = saved.clone();
+        BatchWriter::new(db.clone(), 2, move |outcome| {
+            assert!(outcome.failed.is_empty());
+            saved.lock().unwrap().extend(outcome.saved.into_iter().map(|pending| pending.image.path));
+        })
+    };
+    for path in ["a.jpg", "b.jpg", "c.jpg"] {
+        writer.send(pending(path, "a dog", &[])).unwrap();
+    }
+    writer.finish();
+
+    let mut saved = saved.lock().unwrap().clone();
+    saved.sort();
+    assert_eq!(saved, vec!["a.jpg", "b.jpg", "c.jpg"]);
+    let db = db.lock().unwrap();
+    assert_eq!(db.as_ref().unwrap().select_all_images().unwrap().len(), 3);
+    assert_eq!(db.as_ref().unwrap().keyword_search("dog", 10).unwrap().len(), 3);
+}
+
+#[test]
+fn batch_writer_keeps_good_images_when_one_fails() {
+    let db = Arc::new(Mutex::new(Some(Database::in_memory().unwrap())));
+    let failed = Arc::new(Mutex::new(Vec::new()));
+    let writer = {
+        let failed = failed.clone();
+        BatchWriter::new(db.clone(), 10, move |outcome| {
+            failed.lock().unwrap().extend(outcome.failed.into_iter().map(|(pending, _)| pending.image.path));
+        })
+    };
+    // Paths are unique, so the second `a.jpg` cannot be inserted.
+    for path in ["a.jpg", "b.jpg", "a.jpg"] {
+        writer.send(pending(path, "a dog", &[])).unwrap();
+    }
+    writer.finish();
+
+    assert_eq!(*failed.lock().unwrap(), vec!["a.jpg".to_string()]);
+    assert_eq!(db.lock().unwrap().as_ref().unwrap().select_all_images().unwrap().len(), 2);
+}
@@ -0,0 +1,70 @@
+use db::analysis::ImageAnalysis;
+use db::batch::PendingImage;
+use db::database::Database;
+use db::image::Image;
+
+fn tokens(text: &str) -> Vec<String> {
+    text.split_whitespace().map(|token| token.to_string()).collect()
+}
+
+fn index(db: &mut Database, path: &str, text: &str) -> u32 {
+    let mut batch = [PendingImage {
+        image: Image::new(path.to_string(), path.to_string()),
+        analysis: ImageAnalysis::new(0, text.to_string(), Vec::new()),
+        tokens: tokens(text),
+    }];
+    db.save_batch(&mut batch).unwrap();
+    batch[0].image.id
+}
+
+fn stats(db: &Database) -> (u32, Vec<(String, u32, u32)>) {
+    let (documents, mut terms) = db.select_term_stats().unwrap();
+    terms.sort();
+    (documents, terms)
+}
+
+#[test]
+fn indexed_images_are_counted() {
+    let mut db = Database::in_memory().unwrap();
+    index(&mut db, "a.jpg", "dog on a dog bed");
+    index(&mut db, "b.jpg", "dog in snow");
+
+    let (documents, terms) = stats(&db);
+    assert_eq!(documents, 2);
+    assert!(terms.contains(&("dog".to_string(), 2, 3)));
+    assert!(terms.contains(&("snow".to_string(), 1, 1)));
+}
+
+#[test]
+fn deleted_images_leave_the_stats() {
+    let mut db = Database::in_memory().unwrap();
+    let first = index(&mut db, "a.jpg", "dog on a dog bed");
+    index(&mut db, "b.jpg", "dog in snow");
+
+    db.delete_image(first).unwrap();
+
+    let (documents, terms) = stats(&db);
+    assert_eq!(documents, 1);
+    assert_eq!(terms, vec![
+        ("dog".to_string(), 1, 1),
+        ("in".to_string(), 1, 1),
+        ("snow".to_string(), 1, 1),
+    ]);
+}
+
+#[test]
+fn reanalysed_images_replace_their_terms() {
+    let mut db = Database::in_memory().unwrap();
+    let id = index(&mut db, "a.jpg", "dog in snow");
+
+    db.set_image_terms(id, &tokens("cat on sofa")).unwrap();
+    db.set_image_terms(id, &tokens("cat on sofa")).unwrap();
+
+    let (documents, terms) = stats(&db);
+    assert_eq!(documents, 1);
+    assert_eq!(terms, vec![
+        ("cat".to_string(), 1, 1),
+        ("on".to_string(), 1, 1),
+        ("sofa".to_string(), 1, 1),
+    ]);
+}
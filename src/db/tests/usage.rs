@@ -0,0 +1,57 @@
+use db::database::Database;
+use db::usage::{day_of, ApiUsage};
+
+fn usage(requests: u64, successes: u64, failures: u64) -> ApiUsage {
+    ApiUsage { requests, successes, failures }
+}
+
+#[test]
+fn days_change_at_midnight_utc() {
+    assert_eq!(day_of(0), "1970-01-01");
+    assert_eq!(day_of(86_399), "1970-01-01");
+    assert_eq!(day_of(86_400), "1970-01-02");
+    assert_eq!(day_of(-1), "1969-12-31");
+}
+
+#[test]
+fn days_follow_the_calendar() {
+    assert_eq!(day_of(951_782_400), "2000-02-29");
+    assert_eq!(day_of(951_868_800), "2000-03-01");
+    assert_eq!(day_of(1_709_164_800), "2024-02-29");
+    assert_eq!(day_of(1_735_689_599), "2024-12-31");
+    assert_eq!(day_of(1_735_689_600), "2025-01-01");
+}
+
+#[test]
+fn usage_is_summed_by_day_and_month() {
+    let mut db = Database::usage_in_memory().unwrap();
+    let run = db.start_usage_run().unwrap();
+    db.add_usage(run, "2024-05-31", &usage(3, 2, 1)).unwrap();
+    db.add_usage(run, "2024-05-31", &usage(1, 1, 0)).unwrap();
+    db.add_usage(run, "2024-05-01", &usage(2, 2, 0)).unwrap();
+    db.add_usage(run, "2024-06-01", &usage(5, 4, 1)).unwrap();
+
+    assert_eq!(db.select_usage("2024-05-31").unwrap(), usage(4, 3, 1));
+    assert_eq!(db.select_usage("2024-05").unwrap(), usage(6, 5, 1));
+    assert_eq!(db.select_usage("2024-06").unwrap(), usage(5, 4, 1));
+    assert_eq!(db.select_usage("2024-07").unwrap(), ApiUsage::default());
+    assert_eq!(db.select_last_run_usage().unwrap(), Some(usage(11, 9, 2)));
+}
+
+#[test]
+fn day_totals_are_listed_and_added_without_a_run() {
+    let mut db = Database::usage_in_memory().unwrap();
+    let run = db.start_usage_run().unwrap();
+    db.add_usage(run, "2024-06-01", &usage(5, 4, 1)).unwrap();
+    db.add_day_usage("2024-06-01", &usage(1, 1, 0)).unwrap();
+    db.add_day_usage("2024-05-31", &usage(3, 3, 0)).unwrap();
+
+    assert_eq!(db.select_usage_days().unwrap(), vec![
+        ("2024-05-31".to_string(), usage(3, 3, 0)),
+        ("2024-06-01".to_string(), usage(6, 5, 1)),
+    ]);
+    assert_eq!(db.select_last_run_usage().unwrap(), Some(usage(5, 4, 1)));
+    assert!(db.has_usage_tables().unwrap());
+    db.drop_usage_tables().unwrap();
+    assert!(!db.has_usage_tables().unwrap());
+}
@@ -27,7 +27,7 @@ impl DirWalker {
         let mut handles = vec![];
         let f = Arc::new(f);
 
-        let num_threads = num_cpus::get() - 1;
+        let num_threads = (num_cpus::get() - 1).max(1);
         let sleeping_threads = Arc::new(AtomicUsize::new(0));
         let pair = Arc::new((self.dirs.clone(), Condvar::new()));
 
@@ -0,0 +1,16 @@
+use std::fmt::Write;
+use sha2::{Digest, Sha256};
+
+/// Hex SHA-256 of `bytes`, used to recognise the same photo under another path.
+pub fn content_hash(bytes: &[u8]) -> String {
+    let digest = Sha256::digest(bytes);
+    let mut hash = String::with_capacity(digest.len() * 2);
+    for byte in digest {
+        let _ = write!(hash, "{:02x}", byte);
+    }
+    hash
+}
+
+pub fn file_hash(path: &str) -> std::io::Result<String> {
+    Ok(content_hash(&std::fs::read(path)?))
+}
@@ -1 +1,2 @@
 pub mod dir_walker;
+pub mod hash;
@@ -0,0 +1,305 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use crate::azure_api::{AzureRequest, AzureResponse, BoundingRect, ImageMetadata};
+use crate::config::{AnalyzerConfig, AzureConfig};
+use crate::error::{AnalyzeError, ServiceError};
+use crate::preprocess::{prepare_upload, Preprocess};
+use crate::retry::{classify_error, classify_status, AdaptiveLimiter, Clock, Failure, RetryPolicy, SystemClock};
+
+#[derive(Debug, Clone)]
+pub struct AnalyzedTag {
+    pub name: String,
+    pub confidence: f64,
+}
+
+/// A box given as fractions of the image's width and height, so it fits the image at any
+/// display size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+/// An object found in an image, or with dense captions a described part of it.
+#[derive(Debug, Clone)]
+pub struct DetectedRegion {
+    pub name: String,
+    pub confidence: f64,
+    pub bounds: BoundingBox,
+}
+
+/// What a vision backend says about one image. Results the backend did not return are
+/// left empty, so an analysis with only tags has an empty caption.
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    pub caption: String,
+    pub tags: Vec<AnalyzedTag>,
+    /// Lines of text read from the image, top to bottom. Empty for backends without OCR
+    /// and for images without text.
+    pub lines: Vec<String>,
+    /// Detected objects, named by their best tag.
+    pub objects: Vec<DetectedRegion>,
+    /// Captions of regions of the image, for backends that support dense captions.
+    pub dense_captions: Vec<DetectedRegion>,
+}
+
+impl Analysis {
+    /// Tags plus the names of detected objects, so objects can be searched and filtered
+    /// like tags. A name that is both keeps its higher confidence.
+    pub fn searchable_tags(&self) -> Vec<AnalyzedTag> {
+        let mut tags = self.tags.clone();
+        for object in self.objects.iter() {
+            match tags.iter_mut().find(|tag| tag.name == object.name) {
+                Some(tag) => tag.confidence = tag.confidence.max(object.confidence),
+                None => tags.push(AnalyzedTag { name: object.name.clone(), confidence: object.confidence }),
+            }
+        }
+        tags
+    }
+
+    /// Tag names ordered by confidence, best first.
+    pub fn top_tags(&self, count: usize) -> Vec<String> {
+        let mut tags = self.tags.clone();
+        tags.sort_by(|a, b| b.confidence.partial_cmp(&a.confidence).unwrap_or(std::cmp::Ordering::Equal));
+        tags.into_iter().take(count).map(|tag| tag.name).collect()
+    }
+}
+
+/// Requests sent to a vision service, counted per HTTP request so retries count too.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Usage {
+    pub requests: u64,
+    pub successes: u64,
+    pub failures: u64,
+}
+
+impl Usage {
+    /// What was added since `earlier`, a snapshot of the same counters.
+    pub fn since(&self, earlier: &Usage) -> Usage {
+        Usage {
+            requests: self.requests.saturating_sub(earlier.requests),
+            successes: self.successes.saturating_sub(earlier.successes),
+            failures: self.failures.saturating_sub(earlier.failures),
+        }
+    }
+}
+
+/// Asked before every request to a paid service, retries included, so a call budget holds
+/// even while many images are analysed at once.
+pub trait RequestGate: Send + Sync {
+    /// Claims one request, or says why it may not be sent.
+    fn reserve(&self) -> Result<(), String>;
+}
+
+/// Lets every request through.
+pub struct Unlimited;
+
+impl RequestGate for Unlimited {
+    fn reserve(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// A vision service or local model that captions and tags images.
+///
+/// Implementations are shared between indexing workers, so they must be `Send + Sync`.
+pub trait ImageAnalyzer: Send + Sync {
+    /// Short name shown in logs, e.g. "azure".
+    fn name(&self) -> String;
+
+    /// Analyses `image`, asking `gate` before each request it sends.
+    fn analyze_gated<'a>(&'a self, image: Vec<u8>, gate: &'a dyn RequestGate) -> BoxFuture<'a, Result<Analysis, AnalyzeError>>;
+
+    fn analyze_bytes(&self, image: Vec<u8>) -> BoxFuture<'_, Result<Analysis, AnalyzeError>> {
+        self.analyze_gated(image, &Unlimited)
+    }
+
+    /// Requests sent since the analyzer was created. Backends that call no paid service
+    /// report nothing.
+    fn usage(&self) -> Usage {
+        Usage::default()
+    }
+
+    fn analyze_path<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<Analysis, AnalyzeError>> {
+        async move {
+            let image = tokio::fs::read(path).await?;
+            self.analyze_bytes(image).await
+        }.boxed()
+    }
+}
+
+/// Azure AI Vision Image Analysis 4.0. Throttled and transient failures are retried with
+/// backoff, and all requests share one adaptive rate limit.
+pub struct AzureAnalyzer {
+    config: AzureConfig,
+    retry: RetryPolicy,
+    limiter: AdaptiveLimiter,
+    clock: Arc<dyn Clock>,
+    requests: AtomicU64,
+    successes: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl AzureAnalyzer {
+    /// Fails when the endpoint or key is missing from `config`.
+    pub fn new(config: AzureConfig) -> Result<AzureAnalyzer, String> {
+        config.validate()?;
+        let retry = RetryPolicy {
+            max_attempts: config.max_attempts.max(1),
+            ..RetryPolicy::default()
+        };
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let limiter = AdaptiveLimiter::with_clock(config.requests_per_second, clock.clone());
+        Ok(AzureAnalyzer {
+            config,
+            retry,
+            limiter,
+            clock,
+            requests: AtomicU64::new(0),
+            successes: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+        })
+    }
+
+    /// Starts the exponential backoff at `base_delay` instead of the default half second.
+    pub fn with_backoff(mut self, base_delay: Duration) -> AzureAnalyzer {
+        self.retry.base_delay = base_delay;
+        self
+    }
+
+    /// Waits between attempts and for the rate limit on `clock`, e.g. a `VirtualClock` in tests.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> AzureAnalyzer {
+        self.limiter = AdaptiveLimiter::with_clock(self.config.requests_per_second, clock.clone());
+        self.clock = clock;
+        self
+    }
+
+    /// Requests per second currently allowed by the adaptive limiter.
+    pub fn rate(&self) -> f64 {
+        self.limiter.rate()
+    }
+}
+
+impl ImageAnalyzer for AzureAnalyzer {
+    fn name(&self) -> String {
+        "azure".to_string()
+    }
+
+    fn analyze_gated<'a>(&'a self, image: Vec<u8>, gate: &'a dyn RequestGate) -> BoxFuture<'a, Result<Analysis, AnalyzeError>> {
+        async move {
+            let preprocess = Preprocess { max_edge: self.config.max_edge, jpeg_quality: self.config.jpeg_quality };
+            let upload = tokio::task::spawn_blocking(move || prepare_upload(image, &preprocess)).await
+                .map_err(|e| AnalyzeError::Image(e.to_string()))?
+                .map_err(AnalyzeError::Image)?;
+            let mut request = AzureRequest::new(&self.config)?;
+            request.set_img_bytes(upload);
+            let mut attempt = 0;
+            loop {
+                attempt += 1;
+                self.limiter.until_ready().await;
+                gate.reserve().map_err(AnalyzeError::Refused)?;
+                self.requests.fetch_add(1, Ordering::Relaxed);
+                let (failure, error) = match request.send_request().await {
+                    Ok(response) if response.status().is_success() => {
+                        self.successes.fetch_add(1, Ordering::Relaxed);
+                        self.limiter.on_success();
+                        let body = response.bytes().await?;
+                        return Ok(Analysis::from(AzureResponse::from_body(&body)?));
+                    }
+                    Ok(response) => {
+                        let status = response.status();
+                        let failure = classify_status(status, response.headers());
+                        let body = response.bytes().await.unwrap_or_default();
+                        let error = AnalyzeError::Status { status: status.as_u16(), error: ServiceError::from_body(&body) };
+                        println!("Vision request failed (attempt {}): {}", attempt, error);
+                        (failure, error)
+                    }
+                    Err(e) => {
+                        let failure = classify_error(&e);
+                        let error = AnalyzeError::Request(e);
+                        println!("Vision request failed (attempt {}): {}", attempt, error);
+                        (failure, error)
+                    }
+                };
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                if !failure.is_retryable() {
+                    return Err(error);
+                }
+                if let Failure::Throttled { retry_after } = failure {
+                    self.limiter.on_throttled(retry_after);
+                }
+                if attempt >= self.retry.max_attempts {
+                    return Err(AnalyzeError::GaveUp { attempts: attempt, last: Box::new(error) });
+                }
+                self.clock.sleep(self.retry.delay(attempt, failure.retry_after())).await;
+            }
+        }.boxed()
+    }
+
+    fn usage(&self) -> Usage {
+        Usage {
+            requests: self.requests.load(Ordering::Relaxed),
+            successes: self.successes.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl From<AzureResponse> for Analysis {
+    fn from(response: AzureResponse) -> Self {
+        let lines = response.lines();
+        let metadata = response.metadata;
+        let region = |name: String, confidence: f64, bounds: &BoundingRect| DetectedRegion {
+            name,
+            confidence,
+            bounds: relative_bounds(bounds, metadata),
+        };
+        Analysis {
+            caption: response.caption_result.map(|caption| caption.text).unwrap_or_default(),
+            tags: response.tags_result.map(|tags| tags.values).unwrap_or_default().into_iter()
+                .map(|tag| AnalyzedTag { name: tag.name, confidence: tag.confidence })
+                .collect(),
+            lines,
+            // Objects are named by their best tag; objects without a name are of no use for search.
+            objects: response.objects_result.map(|objects| objects.values).unwrap_or_default().into_iter()
+                .filter_map(|object| {
+                    let tag = object.tags.into_iter().next()?;
+                    Some(region(tag.name, tag.confidence, &object.bounding_box))
+                })
+                .collect(),
+            dense_captions: response.dense_captions_result.map(|captions| captions.values).unwrap_or_default().into_iter()
+                .map(|caption| region(caption.text, caption.confidence, &caption.bounding_box))
+                .collect(),
+        }
+    }
+}
+
+/// Converts a box in pixels to fractions of the image size, clamped to the image. Without
+/// the image size the box covers the whole image.
+fn relative_bounds(rect: &BoundingRect, metadata: Option<ImageMetadata>) -> BoundingBox {
+    let (width, height) = match metadata {
+        Some(metadata) if metadata.width > 0.0 && metadata.height > 0.0 => (metadata.width, metadata.height),
+        _ => return BoundingBox { x: 0.0, y: 0.0, width: 1.0, height: 1.0 },
+    };
+    let x = (rect.x / width).clamp(0.0, 1.0);
+    let y = (rect.y / height).clamp(0.0, 1.0);
+    BoundingBox {
+        x,
+        y,
+        width: (rect.w / width).clamp(0.0, 1.0 - x),
+        height: (rect.h / height).clamp(0.0, 1.0 - y),
+    }
+}
+
+/// Builds the analyzer named by `config.backend`. New backends are added here.
+pub fn analyzer_from_config(config: &AnalyzerConfig) -> Result<Box<dyn ImageAnalyzer>, String> {
+    match config.backend.as_str() {
+        "azure" => Ok(Box::new(AzureAnalyzer::new(config.azure.clone())?)),
+        other => Err(format!("Unknown image analyzer \"{}\"", other)),
+    }
+}
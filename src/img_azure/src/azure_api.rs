@@ -1,8 +1,9 @@
-use std::io::{ErrorKind, Read};
+use std::io::Read;
 use std::time::Duration;
 use reqwest::{Response};
 use serde::Deserialize;
-use serde_json::Value;
+use crate::config::AzureConfig;
+use crate::error::AnalyzeError;
 
 pub struct AzureRequest {
     client: reqwest::Client,
@@ -12,25 +13,30 @@ pub struct AzureRequest {
 }
 
 impl AzureRequest {
-    pub fn new(key: &str, args: Vec<&str>) -> Self {
+    /// Fails when the key cannot be sent as a header, e.g. when it holds a line break.
+    pub fn new(config: &AzureConfig) -> Result<Self, AnalyzeError> {
         let client = reqwest::Client::new();
         let mut headers = reqwest::header::HeaderMap::new();
-        headers.insert("Ocp-Apim-Subscription-Key", reqwest::header::HeaderValue::from_str(key).unwrap());
-        headers.insert("Content-Type", reqwest::header::HeaderValue::from_str("application/octet-stream").unwrap());
+        let key = reqwest::header::HeaderValue::from_str(config.key.trim())
+            .map_err(|_| AnalyzeError::Config("the key contains characters that are not allowed in a header".to_string()))?;
+        headers.insert("Ocp-Apim-Subscription-Key", key);
+        headers.insert("Content-Type", reqwest::header::HeaderValue::from_static("application/octet-stream"));
 
-        AzureRequest {
+        Ok(AzureRequest {
             client,
             headers,
             img: Vec::new(),
-            request_adress: "https://file-search-rust-paid.cognitiveservices.azure.com/computervision/imageanalysis:analyze?api-version=2024-02-01&features=".to_string() + args.join(",").as_str(),
-        }
+            request_adress: config.analyze_url(),
+        })
     }
 
-    pub fn set_img(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        match std::fs::File::open(path)?.read_to_end(&mut self.img) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(Box::new(e)),
-        }
+    pub fn set_img(&mut self, path: &str) -> std::io::Result<()> {
+        std::fs::File::open(path)?.read_to_end(&mut self.img)?;
+        Ok(())
+    }
+
+    pub fn set_img_bytes(&mut self, img: Vec<u8>) {
+        self.img = img;
     }
 
     pub async fn send_request(&self) -> Result<Response, reqwest::Error> {
@@ -46,66 +52,126 @@ impl AzureRequest {
         }
     }
 }
+/// Body of a successful `imageanalysis:analyze` call. Each result is present only when its
+/// feature was requested and the service produced it, so any of them may be missing.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AzureResponse {
+    #[serde(default)]
+    pub model_version: Option<String>,
+    #[serde(default)]
+    pub metadata: Option<ImageMetadata>,
+    #[serde(default)]
+    pub caption_result: Option<CaptionResult>,
+    #[serde(default)]
+    pub dense_captions_result: Option<DenseCaptionsResult>,
+    #[serde(default)]
+    pub tags_result: Option<TagsResult>,
+    #[serde(default)]
+    pub objects_result: Option<ObjectsResult>,
+    #[serde(default)]
+    pub read_result: Option<ReadResult>,
+}
+
+/// Size of the analysed image in pixels.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ImageMetadata {
+    pub width: f64,
+    pub height: f64,
+}
+
 #[derive(Debug, Deserialize)]
-pub struct Label {
-    pub name: String,
-    pub score: f64,
+pub struct CaptionResult {
+    pub text: String,
+    pub confidence: f64,
 }
-impl Label {
-    pub fn new(name: String, score: f64) -> Self {
-        Label {
-            name,
-            score,
-        }
-    }
+
+#[derive(Debug, Deserialize)]
+pub struct DenseCaptionsResult {
+    pub values: Vec<DenseCaption>,
 }
 
-impl From<&Value> for Label {
-    fn from(value: &Value) -> Self {
-        let name = value["name"].as_str().unwrap().to_string();
-        let score = value["confidence"].as_f64().unwrap();
-        Label {
-            name,
-            score,
-        }
-    }
+/// A caption of part of the image; the first one usually covers the whole image.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DenseCaption {
+    pub text: String,
+    pub confidence: f64,
+    pub bounding_box: BoundingRect,
 }
+
 #[derive(Debug, Deserialize)]
-pub struct AzureResponse {
-    pub caption: String,
-    pub labels: Vec<Label>,
+pub struct TagsResult {
+    pub values: Vec<DetectedTag>,
 }
 
-impl TryFrom<Value> for AzureResponse {
-    type Error = ErrorKind;
+#[derive(Debug, Clone, Deserialize)]
+pub struct DetectedTag {
+    pub name: String,
+    pub confidence: f64,
+}
 
-    fn try_from(value: Value) -> Result<Self, Self::Error> {
-        let caption = value["captionResult"]["text"].as_str();
+#[derive(Debug, Deserialize)]
+pub struct ObjectsResult {
+    pub values: Vec<DetectedObject>,
+}
 
-        if caption.is_none() {
-            println!("No caption found");
-            println!("{:?}", value);
-            return Err(Self::Error::from(ErrorKind::NotFound));
-        }
-        let caption = caption.unwrap().to_string();
-        let mut labels = Vec::new();
+/// An object with its candidate names, best first.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DetectedObject {
+    pub bounding_box: BoundingRect,
+    #[serde(default)]
+    pub tags: Vec<DetectedTag>,
+}
 
+/// A box in pixels of the analysed image.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct BoundingRect {
+    pub x: f64,
+    pub y: f64,
+    pub w: f64,
+    pub h: f64,
+}
 
-        let label = value["tagsResult"].get("values");
-        if label.is_none() {
-            println!("No labels found");
-            return Err(Self::Error::from(ErrorKind::NotFound));
-        }
-        let label_it = label.unwrap().as_array().unwrap();
+#[derive(Debug, Deserialize)]
+pub struct ReadResult {
+    #[serde(default)]
+    pub blocks: Vec<ReadBlock>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReadBlock {
+    #[serde(default)]
+    pub lines: Vec<ReadLine>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReadLine {
+    pub text: String,
+}
 
-        for label in label_it {
-            labels.push(Label::new(label.get("name").unwrap().as_str().unwrap().to_string(), label.get("confidence").unwrap().as_f64().unwrap()));
+impl AzureResponse {
+    /// Parses a response body. Fails with `MissingField` when it holds none of the results,
+    /// which happens when the requested features are not supported in the resource's region.
+    pub fn from_body(body: &[u8]) -> Result<AzureResponse, AnalyzeError> {
+        let response: AzureResponse = serde_json::from_slice(body)?;
+        if response.caption_result.is_none()
+            && response.dense_captions_result.is_none()
+            && response.tags_result.is_none()
+            && response.objects_result.is_none()
+            && response.read_result.is_none() {
+            return Err(AnalyzeError::MissingField("captionResult, tagsResult, objectsResult, denseCaptionsResult or readResult"));
         }
+        Ok(response)
+    }
 
-        Ok(AzureResponse {
-            caption,
-            labels,
-        })
+    /// Lines from `readResult`, top to bottom.
+    pub fn lines(&self) -> Vec<String> {
+        self.read_result.iter()
+            .flat_map(|read| read.blocks.iter())
+            .flat_map(|block| block.lines.iter())
+            .map(|line| line.text.clone())
+            .collect()
     }
 }
-
@@ -0,0 +1,33 @@
+use std::time::Duration;
+use img_azure::mock_server::{MockOptions, MockVisionServer};
+
+/// Runs the mock vision server until Ctrl+C. Point the app at it with
+/// `AZURE_VISION_ENDPOINT=http://127.0.0.1:5055 AZURE_VISION_KEY=mock`.
+///
+/// Settings come from the environment: `MOCK_VISION_ADDRESS` (default `127.0.0.1:5055`),
+/// `MOCK_VISION_LATENCY_MS`, `MOCK_VISION_THROTTLE_EVERY`, `MOCK_VISION_FAIL_EVERY`
+/// and `MOCK_VISION_RETRY_AFTER`.
+#[tokio::main]
+async fn main() {
+    let address = std::env::var("MOCK_VISION_ADDRESS").unwrap_or("127.0.0.1:5055".to_string());
+    let options = MockOptions {
+        latency: Duration::from_millis(env_number("MOCK_VISION_LATENCY_MS", 0) as u64),
+        throttle_every: env_number("MOCK_VISION_THROTTLE_EVERY", 0),
+        fail_every: env_number("MOCK_VISION_FAIL_EVERY", 0),
+        retry_after: env_number("MOCK_VISION_RETRY_AFTER", 1) as u64,
+    };
+    let server = match MockVisionServer::bind(&address, options.clone()).await {
+        Ok(server) => server,
+        Err(e) => {
+            println!("Unable to bind {}: {}", address, e);
+            return;
+        }
+    };
+    println!("Mock vision server listening on {} with {:?}", server.endpoint(), options);
+    let _ = tokio::signal::ctrl_c().await;
+    println!("Served {} requests", server.requests());
+}
+
+fn env_number(name: &str, default: usize) -> usize {
+    std::env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
@@ -0,0 +1,212 @@
+use serde::Deserialize;
+
+pub const CONFIG_PATH: &str = "./config.json";
+/// Overrides `analyzer.backend` from the config file.
+pub const BACKEND_ENV: &str = "FILE_SEARCH_ANALYZER";
+pub const AZURE_KEY_ENV: &str = "AZURE_VISION_KEY";
+pub const AZURE_ENDPOINT_ENV: &str = "AZURE_VISION_ENDPOINT";
+pub const AZURE_API_VERSION_ENV: &str = "AZURE_VISION_API_VERSION";
+/// Comma separated, e.g. `tags,caption`.
+pub const AZURE_FEATURES_ENV: &str = "AZURE_VISION_FEATURES";
+
+/// Which vision backend to use and its settings, read from `./config.json`, e.g.
+/// ```json
+/// {
+///   "analyzer": { "backend": "azure", "daily_budget": 1000, "monthly_budget": 20000 },
+///   "azure": {
+///     "endpoint": "https://<resource>.cognitiveservices.azure.com",
+///     "key": "<subscription key>",
+///     "api_version": "2024-02-01",
+///     "features": ["tags", "caption", "read", "objects"],
+///     "requests_per_second": 10,
+///     "max_attempts": 5,
+///     "max_edge": 2048,
+///     "jpeg_quality": 85
+///   }
+/// }
+/// ```
+/// Environment variables override the file.
+#[derive(Debug, Clone)]
+pub struct AnalyzerConfig {
+    pub backend: String,
+    pub budget: CallBudget,
+    pub azure: AzureConfig,
+}
+
+/// Most requests the vision service may be sent per UTC day and per calendar month;
+/// `None` is unlimited. Indexing pauses once either is used up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+pub struct CallBudget {
+    #[serde(default, rename = "daily_budget")]
+    pub daily: Option<u64>,
+    #[serde(default, rename = "monthly_budget")]
+    pub monthly: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AzureConfig {
+    #[serde(default)]
+    pub endpoint: String,
+    #[serde(default)]
+    pub key: String,
+    #[serde(default = "default_api_version")]
+    pub api_version: String,
+    #[serde(default = "default_features")]
+    pub features: Vec<String>,
+    /// Upper bound for the adaptive rate limit.
+    #[serde(default = "default_requests_per_second")]
+    pub requests_per_second: f64,
+    /// Attempts per image, including the first, before a throttled or failing request is given up.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Images are scaled down so neither side is longer than this before upload.
+    #[serde(default = "default_max_edge")]
+    pub max_edge: u32,
+    #[serde(default = "default_jpeg_quality")]
+    pub jpeg_quality: u8,
+}
+
+impl Default for AzureConfig {
+    fn default() -> Self {
+        AzureConfig {
+            endpoint: String::new(),
+            key: String::new(),
+            api_version: default_api_version(),
+            features: default_features(),
+            requests_per_second: default_requests_per_second(),
+            max_attempts: default_max_attempts(),
+            max_edge: default_max_edge(),
+            jpeg_quality: default_jpeg_quality(),
+        }
+    }
+}
+
+impl AzureConfig {
+    /// Checks that the endpoint and key are set, naming where to set whichever is missing.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut missing = Vec::new();
+        if self.endpoint.trim().is_empty() {
+            missing.push(format!("endpoint (azure.endpoint in {} or {})", CONFIG_PATH, AZURE_ENDPOINT_ENV));
+        }
+        if self.key.trim().is_empty() {
+            missing.push(format!("key (azure.key in {} or {})", CONFIG_PATH, AZURE_KEY_ENV));
+        } else if reqwest::header::HeaderValue::from_str(self.key.trim()).is_err() {
+            return Err("Azure vision key contains characters that are not allowed in a header".to_string());
+        }
+        if self.max_edge < 50 {
+            return Err("azure.max_edge must be at least 50 pixels".to_string());
+        }
+        if self.features.is_empty() {
+            missing.push(format!("features (azure.features in {} or {})", CONFIG_PATH, AZURE_FEATURES_ENV));
+        }
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Azure vision is not configured, missing {}", missing.join(", ")))
+        }
+    }
+
+    /// `<endpoint>/computervision/imageanalysis:analyze?api-version=..&features=..`
+    pub fn analyze_url(&self) -> String {
+        format!(
+            "{}/computervision/imageanalysis:analyze?api-version={}&features={}",
+            self.endpoint.trim().trim_end_matches('/'),
+            self.api_version,
+            self.features.join(","),
+        )
+    }
+
+    fn apply_env(&mut self) {
+        if let Ok(endpoint) = std::env::var(AZURE_ENDPOINT_ENV) {
+            self.endpoint = endpoint;
+        }
+        if let Ok(key) = std::env::var(AZURE_KEY_ENV) {
+            self.key = key;
+        }
+        if let Ok(api_version) = std::env::var(AZURE_API_VERSION_ENV) {
+            self.api_version = api_version;
+        }
+        if let Ok(features) = std::env::var(AZURE_FEATURES_ENV) {
+            self.features = features.split(',')
+                .map(|feature| feature.trim().to_string())
+                .filter(|feature| !feature.is_empty())
+                .collect();
+        }
+    }
+}
+
+fn default_backend() -> String {
+    "azure".to_string()
+}
+
+fn default_api_version() -> String {
+    "2024-02-01".to_string()
+}
+
+fn default_features() -> Vec<String> {
+    vec!["tags".to_string(), "caption".to_string(), "read".to_string(), "objects".to_string()]
+}
+
+fn default_requests_per_second() -> f64 {
+    10.0
+}
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+fn default_max_edge() -> u32 {
+    2048
+}
+
+fn default_jpeg_quality() -> u8 {
+    85
+}
+
+#[derive(Debug, Deserialize)]
+struct AnalyzerSection {
+    #[serde(default = "default_backend")]
+    backend: String,
+    #[serde(flatten)]
+    budget: CallBudget,
+}
+
+impl Default for AnalyzerSection {
+    fn default() -> Self {
+        AnalyzerSection { backend: default_backend(), budget: CallBudget::default() }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    analyzer: AnalyzerSection,
+    #[serde(default)]
+    azure: AzureConfig,
+}
+
+impl AnalyzerConfig {
+    /// Reads `./config.json` if it exists, then applies environment overrides.
+    pub fn load() -> Result<AnalyzerConfig, String> {
+        AnalyzerConfig::load_from(CONFIG_PATH)
+    }
+
+    pub fn load_from(path: &str) -> Result<AnalyzerConfig, String> {
+        let file = match std::fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str::<ConfigFile>(&text)
+                .map_err(|e| format!("{}: {}", path, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ConfigFile::default(),
+            Err(e) => return Err(format!("{}: {}", path, e)),
+        };
+        let mut config = AnalyzerConfig {
+            backend: file.analyzer.backend,
+            budget: file.analyzer.budget,
+            azure: file.azure,
+        };
+        if let Ok(backend) = std::env::var(BACKEND_ENV) {
+            config.backend = backend;
+        }
+        config.azure.apply_env();
+        Ok(config)
+    }
+}
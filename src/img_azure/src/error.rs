@@ -0,0 +1,93 @@
+use std::fmt;
+use serde::Deserialize;
+
+/// The `error` object the service sends with failed requests.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ServiceError {
+    pub code: String,
+    pub message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ServiceErrorBody {
+    error: ServiceError,
+}
+
+impl ServiceError {
+    /// Reads `{"error": {"code", "message"}}`; other bodies give `None`.
+    pub fn from_body(body: &[u8]) -> Option<ServiceError> {
+        serde_json::from_slice::<ServiceErrorBody>(body).ok().map(|body| body.error)
+    }
+}
+
+#[derive(Debug)]
+pub enum AnalyzeError {
+    /// The image file could not be read.
+    Io(std::io::Error),
+    /// The image could not be decoded or re-encoded for upload.
+    Image(String),
+    /// The request was not sent or no response arrived.
+    Request(reqwest::Error),
+    /// The service answered with an error status, and with its error body when it sent one.
+    Status { status: u16, error: Option<ServiceError> },
+    /// The response body is not the JSON the service documents.
+    Body(String),
+    /// The response holds none of the requested results.
+    MissingField(&'static str),
+    /// Every attempt was throttled or failed; holds the last failure.
+    GaveUp { attempts: u32, last: Box<AnalyzeError> },
+    /// The configuration cannot be turned into a request, e.g. a key that is not a valid header.
+    Config(String),
+    /// The request was not sent because the caller's `RequestGate` refused it, e.g. over budget.
+    Refused(String),
+}
+
+impl AnalyzeError {
+    /// The HTTP status of a rejected request, looking through `GaveUp`.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            AnalyzeError::Status { status, .. } => Some(*status),
+            AnalyzeError::GaveUp { last, .. } => last.status(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AnalyzeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnalyzeError::Io(e) => write!(f, "unable to read image: {}", e),
+            AnalyzeError::Image(e) => write!(f, "unable to prepare image for upload: {}", e),
+            AnalyzeError::Request(e) => write!(f, "vision request failed: {}", e),
+            AnalyzeError::Status { status, error: Some(error) } => {
+                write!(f, "vision service answered {}: {} ({})", status, error.message, error.code)
+            }
+            AnalyzeError::Status { status, error: None } => write!(f, "vision service answered {}", status),
+            AnalyzeError::Body(e) => write!(f, "unexpected vision response: {}", e),
+            AnalyzeError::MissingField(field) => write!(f, "vision response has no {}", field),
+            AnalyzeError::GaveUp { attempts, last } => write!(f, "gave up after {} attempts: {}", attempts, last),
+            AnalyzeError::Config(e) => write!(f, "invalid vision service configuration: {}", e),
+            AnalyzeError::Refused(reason) => write!(f, "vision request not sent: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for AnalyzeError {}
+
+impl From<std::io::Error> for AnalyzeError {
+    fn from(e: std::io::Error) -> Self {
+        AnalyzeError::Io(e)
+    }
+}
+
+impl From<reqwest::Error> for AnalyzeError {
+    fn from(e: reqwest::Error) -> Self {
+        AnalyzeError::Request(e)
+    }
+}
+
+impl From<serde_json::Error> for AnalyzeError {
+    fn from(e: serde_json::Error) -> Self {
+        AnalyzeError::Body(e.to_string())
+    }
+}
@@ -1,19 +1,24 @@
-use std::io::ErrorKind;
-use serde_json::Value;
 use crate::azure_api::{AzureRequest, AzureResponse};
+use crate::config::AzureConfig;
+use crate::error::{AnalyzeError, ServiceError};
 
+pub mod analyzer;
 pub mod azure_api;
+pub mod config;
+pub mod error;
+pub mod mock_server;
+pub mod preprocess;
+pub mod retry;
 
-pub async fn get_response_by_path(path_str: &str) -> Result<AzureResponse, ErrorKind> {
-    let mut request = AzureRequest::new("4d7bd39a70c249eebd19f5b8d62f5d7b", vec!["tags", "caption"]);
-    request.set_img(path_str).unwrap();
-    let response = request.send_request().await;
-    if response.is_err() {
-        println!("{:?}", response);
-        return Err(ErrorKind::InvalidData)
+/// Sends the file at `path_str` once, as it is, without preprocessing or retries.
+pub async fn get_response_by_path(config: &AzureConfig, path_str: &str) -> Result<AzureResponse, AnalyzeError> {
+    let mut request = AzureRequest::new(config)?;
+    request.set_img(path_str)?;
+    let response = request.send_request().await?;
+    let status = response.status();
+    let body = response.bytes().await?;
+    if !status.is_success() {
+        return Err(AnalyzeError::Status { status: status.as_u16(), error: ServiceError::from_body(&body) });
     }
-    let response = response.unwrap();
-    let response_copy = response.json::<Value>().await.unwrap();
-    let response_struct: Result<AzureResponse, ErrorKind> = AzureResponse::try_from(response_copy.clone());
-    response_struct
-}
\ No newline at end of file
+    AzureResponse::from_body(&body)
+}
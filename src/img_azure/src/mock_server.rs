@@ -0,0 +1,303 @@
+use std::io;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use image::ImageReader;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+const ANALYZE_PATH: &str = "/computervision/imageanalysis:analyze";
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+
+const SUBJECTS: [&str; 8] = ["dog", "cat", "bicycle", "car", "tree", "house", "boat", "person"];
+const PLACES: [&str; 6] = ["beach", "street", "forest", "field", "river", "city"];
+
+/// How the mock misbehaves. Counters are per server and start at 1, so with
+/// `throttle_every: 3` the 3rd, 6th, ... requests are answered with 429.
+#[derive(Debug, Clone)]
+pub struct MockOptions {
+    /// Delay before every response.
+    pub latency: Duration,
+    /// Answer every n-th request with 429 Too Many Requests; 0 never does.
+    pub throttle_every: usize,
+    /// Answer every n-th request with 500 Internal Server Error; 0 never does.
+    pub fail_every: usize,
+    /// Seconds sent in the `Retry-After` header of 429 responses.
+    pub retry_after: u64,
+}
+
+impl Default for MockOptions {
+    fn default() -> Self {
+        MockOptions {
+            latency: Duration::ZERO,
+            throttle_every: 0,
+            fail_every: 0,
+            retry_after: 1,
+        }
+    }
+}
+
+/// Local stand-in for the Azure `imageanalysis:analyze` endpoint, for tests and offline
+/// development. Captions and tags are derived from a hash of the uploaded bytes, so the
+/// same image always gets the same answer. Stops when dropped.
+pub struct MockVisionServer {
+    address: SocketAddr,
+    requests: Arc<AtomicUsize>,
+    task: JoinHandle<()>,
+}
+
+impl MockVisionServer {
+    /// Starts the server on a free port of 127.0.0.1.
+    pub async fn start(options: MockOptions) -> io::Result<MockVisionServer> {
+        MockVisionServer::bind("127.0.0.1:0", options).await
+    }
+
+    pub async fn bind(address: &str, options: MockOptions) -> io::Result<MockVisionServer> {
+        let listener = TcpListener::bind(address).await?;
+        let address = listener.local_addr()?;
+        let requests = Arc::new(AtomicUsize::new(0));
+        let options = Arc::new(options);
+        let counter = requests.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let (stream, _) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        println!("Mock vision server: {}", e);
+                        continue;
+                    }
+                };
+                let options = options.clone();
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve(stream, &options, &counter).await {
+                        println!("Mock vision server: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(MockVisionServer { address, requests, task })
+    }
+
+    /// Value for `azure.endpoint`, e.g. `http://127.0.0.1:5055`.
+    pub fn endpoint(&self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    /// Number of requests received so far, including rejected ones.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for MockVisionServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct Request {
+    method: String,
+    target: String,
+    key: Option<String>,
+    body: Vec<u8>,
+}
+
+async fn serve(mut stream: TcpStream, options: &MockOptions, counter: &AtomicUsize) -> io::Result<()> {
+    let request = match read_request(&mut stream).await? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+    let number = counter.fetch_add(1, Ordering::SeqCst) + 1;
+    let (path, query) = request.target.split_once('?').unwrap_or((request.target.as_str(), ""));
+    if request.method != "POST" || path != ANALYZE_PATH {
+        return respond(&mut stream, 404, &[], error_body("NotFound", "Resource not found")).await;
+    }
+
+    if !options.latency.is_zero() {
+        tokio::time::sleep(options.latency).await;
+    }
+    if request.key.as_deref().unwrap_or_default().is_empty() {
+        return respond(&mut stream, 401, &[], error_body("401", "Access denied due to missing subscription key")).await;
+    }
+    if options.throttle_every > 0 && number.is_multiple_of(options.throttle_every) {
+        let retry_after = options.retry_after.to_string();
+        return respond(&mut stream, 429, &[("Retry-After", retry_after.as_str())],
+                       error_body("429", "Rate limit is exceeded")).await;
+    }
+    if options.fail_every > 0 && number.is_multiple_of(options.fail_every) {
+        return respond(&mut stream, 500, &[], error_body("InternalServerError", "An unexpected error occurred")).await;
+    }
+    if request.body.is_empty() {
+        return respond(&mut stream, 400, &[], error_body("InvalidRequest", "Image must not be empty")).await;
+    }
+
+    let features = query.split('&')
+        .filter_map(|pair| pair.strip_prefix("features="))
+        .flat_map(|features| features.split(','))
+        .map(|feature| feature.to_string())
+        .collect::<Vec<String>>();
+    respond(&mut stream, 200, &[], analysis_body(&request.body, &features)).await
+}
+
+async fn read_request(stream: &mut TcpStream) -> io::Result<Option<Request>> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 8192];
+    let header_end = loop {
+        if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+        if buffer.len() > MAX_HEADER_BYTES {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request headers too large"));
+        }
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let target = request_line.next().unwrap_or_default().to_string();
+    let mut content_length = 0;
+    let mut key = None;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.parse().unwrap_or(0),
+                "ocp-apim-subscription-key" => key = Some(value.to_string()),
+                _ => {}
+            }
+        }
+    }
+
+    let mut body = buffer[header_end..].to_vec();
+    while body.len() < content_length {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..read]);
+    }
+    body.truncate(content_length);
+    Ok(Some(Request { method, target, key, body }))
+}
+
+async fn respond(stream: &mut TcpStream, status: u16, headers: &[(&str, &str)], body: Value) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        _ => "Internal Server Error",
+    };
+    let body = body.to_string();
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status, reason, body.len(),
+    );
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str("\r\n");
+    response.push_str(&body);
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn error_body(code: &str, message: &str) -> Value {
+    json!({ "error": { "code": code, "message": message } })
+}
+
+/// FNV-1a, so answers stay the same across runs and platforms.
+fn fingerprint(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// The caption and tags the mock gives an image with these bytes.
+pub fn mock_analysis(bytes: &[u8]) -> (String, Vec<(String, f64)>) {
+    let hash = fingerprint(bytes);
+    let subject = SUBJECTS[(hash % SUBJECTS.len() as u64) as usize];
+    let place = PLACES[((hash >> 8) % PLACES.len() as u64) as usize];
+    let outdoor = (hash >> 16).is_multiple_of(2);
+    let caption = format!("a {} in a {}", subject, place);
+    let tags = vec![
+        (subject.to_string(), 0.9 + ((hash >> 24) % 90) as f64 / 1000.0),
+        (place.to_string(), 0.7 + ((hash >> 32) % 200) as f64 / 1000.0),
+        (if outdoor { "outdoor" } else { "indoor" }.to_string(), 0.5 + ((hash >> 40) % 200) as f64 / 1000.0),
+    ];
+    (caption, tags)
+}
+
+/// The box, in pixels of a `width` x `height` image, around the subject of an image with
+/// these bytes: between a quarter and three quarters of each side, somewhere inside the image.
+pub fn mock_object_box(bytes: &[u8], width: u32, height: u32) -> (u32, u32, u32, u32) {
+    let hash = fingerprint(bytes);
+    let w = width / 4 + (width / 2) * ((hash >> 44) % 100) as u32 / 100;
+    let h = height / 4 + (height / 2) * ((hash >> 52) % 100) as u32 / 100;
+    let x = (width - w) * ((hash >> 4) % 100) as u32 / 100;
+    let y = (height - h) * ((hash >> 12) % 100) as u32 / 100;
+    (x, y, w, h)
+}
+
+fn analysis_body(image: &[u8], features: &[String]) -> Value {
+    let (caption, tags) = mock_analysis(image);
+    // Undecodable uploads get a zero size, like the service's metadata would be missing.
+    let (width, height) = ImageReader::new(Cursor::new(image))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok())
+        .unwrap_or((0, 0));
+    let mut body = json!({
+        "modelVersion": "2023-10-01",
+        "metadata": { "width": width, "height": height },
+    });
+    if features.iter().any(|feature| feature == "caption") {
+        body["captionResult"] = json!({ "text": caption, "confidence": 0.8 });
+    }
+    if features.iter().any(|feature| feature == "tags") {
+        body["tagsResult"] = json!({
+            "values": tags.iter()
+                .map(|(name, confidence)| json!({ "name": name, "confidence": confidence }))
+                .collect::<Vec<Value>>(),
+        });
+    }
+    if features.iter().any(|feature| feature == "read") {
+        body["readResult"] = json!({
+            "blocks": [{ "lines": [{ "text": format!("PHOTO {:08X}", fingerprint(image) >> 32) }] }],
+        });
+    }
+    let (x, y, w, h) = mock_object_box(image, width, height);
+    let subject = &tags[0];
+    if features.iter().any(|feature| feature == "objects") {
+        body["objectsResult"] = json!({
+            "values": [{
+                "boundingBox": { "x": x, "y": y, "w": w, "h": h },
+                "tags": [{ "name": subject.0, "confidence": subject.1 }],
+            }],
+        });
+    }
+    if features.iter().any(|feature| feature == "denseCaptions") {
+        body["denseCaptionsResult"] = json!({
+            "values": [
+                { "text": caption, "confidence": 0.8, "boundingBox": { "x": 0, "y": 0, "w": width, "h": height } },
+                { "text": format!("a close up of a {}", subject.0), "confidence": 0.7, "boundingBox": { "x": x, "y": y, "w": w, "h": h } },
+            ],
+        });
+    }
+    body
+}
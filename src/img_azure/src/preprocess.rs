@@ -0,0 +1,56 @@
+use std::io::Cursor;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+
+/// JPEGs up to this size that need no rotation or scaling are uploaded untouched.
+pub const PASS_THROUGH_BYTES: usize = 4 * 1024 * 1024;
+
+/// How images are shrunk before they are uploaded.
+#[derive(Debug, Clone, Copy)]
+pub struct Preprocess {
+    /// Longest side, in pixels, of the uploaded image.
+    pub max_edge: u32,
+    /// JPEG quality from 1 to 100.
+    pub jpeg_quality: u8,
+}
+
+/// Turns an image file into what is uploaded: rotated upright according to its EXIF
+/// orientation, scaled down so neither side exceeds `max_edge`, and re-encoded as JPEG.
+/// Small upright JPEGs are returned as they are.
+pub fn prepare_upload(bytes: Vec<u8>, options: &Preprocess) -> Result<Vec<u8>, String> {
+    let (format, orientation, width, height) = {
+        let (format, mut decoder) = decoder(&bytes)?;
+        let (width, height) = decoder.dimensions();
+        (format, decoder.orientation().unwrap_or(Orientation::NoTransforms), width, height)
+    };
+    if format == Some(ImageFormat::Jpeg)
+        && orientation == Orientation::NoTransforms
+        && width.max(height) <= options.max_edge
+        && bytes.len() <= PASS_THROUGH_BYTES {
+        return Ok(bytes);
+    }
+
+    let (_, decoder) = decoder(&bytes)?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(|e| e.to_string())?;
+    image.apply_orientation(orientation);
+    if image.width().max(image.height()) > options.max_edge {
+        // `resize` keeps the aspect ratio and fits the image inside the box.
+        image = image.resize(options.max_edge, options.max_edge, FilterType::Triangle);
+    }
+
+    let mut upload = Vec::new();
+    JpegEncoder::new_with_quality(&mut upload, options.jpeg_quality.clamp(1, 100))
+        .encode_image(&image.to_rgb8())
+        .map_err(|e| e.to_string())?;
+    Ok(upload)
+}
+
+fn decoder(bytes: &[u8]) -> Result<(Option<ImageFormat>, impl ImageDecoder + '_), String> {
+    let reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| e.to_string())?;
+    let format = reader.format();
+    Ok((format, reader.into_decoder().map_err(|e| e.to_string())?))
+}
@@ -0,0 +1,231 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+
+/// How often and how patiently a request is retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Attempts including the first one.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with full jitter: a random delay up to `base_delay * 2^(attempt - 1)`,
+    /// capped at `max_delay`. `attempt` starts at 1 for the first retry.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(16);
+        let ceiling = self.base_delay.saturating_mul(1 << exponent).min(self.max_delay);
+        ceiling.mul_f64(jitter())
+    }
+
+    /// The server's `Retry-After` wins over the computed backoff, but is capped at `max_delay`.
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        match retry_after {
+            Some(retry_after) => retry_after.min(self.max_delay),
+            None => self.backoff(attempt),
+        }
+    }
+}
+
+/// Whether a failed attempt is worth repeating.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Failure {
+    /// The service asked us to slow down (429, or 503 with `Retry-After`).
+    Throttled { retry_after: Option<Duration> },
+    /// Timeouts, dropped connections and 5xx responses that may succeed next time.
+    Transient { retry_after: Option<Duration> },
+    /// Bad key, bad image, unknown endpoint: repeating the request cannot help.
+    Permanent,
+}
+
+impl Failure {
+    pub fn is_retryable(&self) -> bool {
+        !matches!(self, Failure::Permanent)
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Failure::Throttled { retry_after } | Failure::Transient { retry_after } => *retry_after,
+            Failure::Permanent => None,
+        }
+    }
+}
+
+/// Classifies a non-success HTTP response.
+pub fn classify_status(status: StatusCode, headers: &HeaderMap) -> Failure {
+    let retry_after = retry_after(headers);
+    match status.as_u16() {
+        429 => Failure::Throttled { retry_after },
+        503 if retry_after.is_some() => Failure::Throttled { retry_after },
+        408 | 500 | 502 | 503 | 504 => Failure::Transient { retry_after },
+        _ => Failure::Permanent,
+    }
+}
+
+/// Classifies an error raised before any response arrived.
+pub fn classify_error(error: &reqwest::Error) -> Failure {
+    if error.is_timeout() || error.is_connect() || error.is_request() || error.is_body() {
+        Failure::Transient { retry_after: None }
+    } else {
+        Failure::Permanent
+    }
+}
+
+/// Reads `retry-after-ms` or `Retry-After` in seconds. HTTP dates are not supported
+/// and fall back to the computed backoff.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = |name: &str| headers.get(name).and_then(|value| value.to_str().ok()).map(|value| value.trim().to_string());
+    if let Some(millis) = value("retry-after-ms").and_then(|value| value.parse::<u64>().ok()) {
+        return Some(Duration::from_millis(millis));
+    }
+    value("retry-after")
+        .and_then(|value| value.parse::<f64>().ok())
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+/// A number in `[0, 1)`, different on every call.
+fn jitter() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos());
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Where the limiter and the retry loop read the time and wait, so tests can skip the waiting.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()>;
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.sleep_until(self.now() + duration)
+    }
+}
+
+/// Real time, waiting on the tokio timer.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        tokio::time::sleep_until(deadline.into()).boxed()
+    }
+}
+
+/// Time that only moves when someone sleeps: every sleep returns at once and moves the
+/// clock to its deadline. Lets tests check waits of seconds in microseconds.
+pub struct VirtualClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl VirtualClock {
+    pub fn new() -> VirtualClock {
+        VirtualClock { start: Instant::now(), elapsed: Mutex::new(Duration::ZERO) }
+    }
+
+    /// How far sleeping has moved the clock.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        VirtualClock::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        let mut elapsed = self.elapsed.lock().unwrap();
+        *elapsed = (*elapsed).max(deadline.saturating_duration_since(self.start));
+        futures::future::ready(()).boxed()
+    }
+}
+
+struct LimiterState {
+    rate: f64,
+    next_slot: Instant,
+}
+
+/// Spaces requests out to at most `rate` per second. The rate is halved whenever the
+/// service throttles and creeps back up to `max_rate` as requests succeed (AIMD), and a
+/// `Retry-After` pauses every caller, not just the throttled one.
+pub struct AdaptiveLimiter {
+    max_rate: f64,
+    min_rate: f64,
+    clock: Arc<dyn Clock>,
+    state: Mutex<LimiterState>,
+}
+
+impl AdaptiveLimiter {
+    pub fn new(max_rate: f64) -> AdaptiveLimiter {
+        AdaptiveLimiter::with_clock(max_rate, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(max_rate: f64, clock: Arc<dyn Clock>) -> AdaptiveLimiter {
+        let max_rate = max_rate.max(0.1);
+        let now = clock.now();
+        AdaptiveLimiter {
+            max_rate,
+            min_rate: (max_rate / 20.0).clamp(0.1, 1.0),
+            clock,
+            state: Mutex::new(LimiterState { rate: max_rate, next_slot: now }),
+        }
+    }
+
+    /// Requests per second currently allowed.
+    pub fn rate(&self) -> f64 {
+        self.state.lock().unwrap().rate
+    }
+
+    /// Waits for this caller's turn.
+    pub async fn until_ready(&self) {
+        let slot = {
+            let mut state = self.state.lock().unwrap();
+            let slot = state.next_slot.max(self.clock.now());
+            state.next_slot = slot + Duration::from_secs_f64(1.0 / state.rate);
+            slot
+        };
+        self.clock.sleep_until(slot).await;
+    }
+
+    pub fn on_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.rate = (state.rate + self.max_rate / 20.0).min(self.max_rate);
+    }
+
+    pub fn on_throttled(&self, retry_after: Option<Duration>) {
+        let mut state = self.state.lock().unwrap();
+        state.rate = (state.rate / 2.0).max(self.min_rate);
+        if let Some(retry_after) = retry_after {
+            state.next_slot = state.next_slot.max(self.clock.now() + retry_after);
+        }
+        println!("Throttled by the vision service, slowing down to {:.1} requests/s", state.rate);
+    }
+}
@@ -0,0 +1,61 @@
+use img_azure::analyzer::Analysis;
+use img_azure::azure_api::{AzureRequest, AzureResponse};
+use img_azure::config::AzureConfig;
+use img_azure::error::{AnalyzeError, ServiceError};
+
+#[test]
+fn parses_every_feature() {
+    let body = br#"{
+        "modelVersion": "2023-10-01",
+        "metadata": { "width": 200, "height": 100 },
+        "captionResult": { "text": "a dog on a beach", "confidence": 0.8 },
+        "tagsResult": { "values": [{ "name": "dog", "confidence": 0.99 }, { "name": "beach", "confidence": 0.9 }] },
+        "objectsResult": { "values": [{ "boundingBox": { "x": 50, "y": 25, "w": 100, "h": 50 }, "tags": [{ "name": "dog", "confidence": 0.7 }] }] },
+        "denseCaptionsResult": { "values": [{ "text": "a dog", "confidence": 0.6, "boundingBox": { "x": 0, "y": 0, "w": 200, "h": 100 } }] },
+        "readResult": { "blocks": [{ "lines": [{ "text": "BEACH", "boundingPolygon": [], "words": [] }, { "text": "NO DOGS" }] }] }
+    }"#;
+    let analysis = Analysis::from(AzureResponse::from_body(body).unwrap());
+    assert_eq!(analysis.caption, "a dog on a beach");
+    assert_eq!(analysis.tags.len(), 2);
+    assert_eq!(analysis.lines, vec!["BEACH".to_string(), "NO DOGS".to_string()]);
+    assert_eq!(analysis.objects[0].name, "dog");
+    assert_eq!((analysis.objects[0].bounds.x, analysis.objects[0].bounds.width), (0.25, 0.5));
+    assert_eq!(analysis.dense_captions[0].bounds.height, 1.0);
+}
+
+#[test]
+fn missing_features_give_partial_results() {
+    let body = br#"{ "tagsResult": { "values": [{ "name": "dog", "confidence": 0.99 }] } }"#;
+    let analysis = Analysis::from(AzureResponse::from_body(body).unwrap());
+    assert_eq!(analysis.caption, "");
+    assert_eq!(analysis.tags[0].name, "dog");
+    assert!(analysis.lines.is_empty() && analysis.objects.is_empty());
+}
+
+#[test]
+fn responses_without_results_are_rejected() {
+    let body = br#"{ "modelVersion": "2023-10-01", "metadata": { "width": 1, "height": 1 } }"#;
+    assert!(matches!(AzureResponse::from_body(body).unwrap_err(), AnalyzeError::MissingField(_)));
+}
+
+#[test]
+fn malformed_results_are_body_errors() {
+    let body = br#"{ "captionResult": { "confidence": 0.8 } }"#;
+    assert!(matches!(AzureResponse::from_body(body).unwrap_err(), AnalyzeError::Body(_)));
+    assert!(matches!(AzureResponse::from_body(b"<html>").unwrap_err(), AnalyzeError::Body(_)));
+}
+
+#[test]
+fn service_error_bodies_are_read() {
+    let error = ServiceError::from_body(br#"{ "error": { "code": "InvalidImageSize", "message": "Image is too small" } }"#);
+    assert_eq!(error, Some(ServiceError { code: "InvalidImageSize".to_string(), message: "Image is too small".to_string() }));
+    assert_eq!(ServiceError::from_body(b"Bad gateway"), None);
+}
+
+#[test]
+fn keys_that_are_not_valid_headers_are_config_errors() {
+    let config = AzureConfig { endpoint: "http://127.0.0.1:1".to_string(), key: "abc\ndef".to_string(), ..Default::default() };
+    assert!(matches!(AzureRequest::new(&config), Err(AnalyzeError::Config(_))));
+    let config = AzureConfig { key: " abc ".to_string(), ..config };
+    assert!(AzureRequest::new(&config).is_ok());
+}
@@ -0,0 +1,282 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use image::codecs::jpeg::JpegEncoder;
+use image::{GenericImageView, RgbImage};
+use img_azure::analyzer::{AzureAnalyzer, ImageAnalyzer, RequestGate};
+use img_azure::config::AzureConfig;
+use img_azure::error::AnalyzeError;
+use img_azure::mock_server::{mock_analysis, mock_object_box, MockOptions, MockVisionServer};
+use img_azure::preprocess::{prepare_upload, Preprocess};
+use img_azure::retry::VirtualClock;
+
+/// The smallest photo of `photos_test`; scaling one down takes seconds, so only tests about
+/// preprocessing use it.
+fn smallest_test_photo() -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../photos_test");
+    std::fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .min_by_key(|path| (std::fs::metadata(path).unwrap().len(), path.clone()))
+        .unwrap()
+}
+
+/// What the mock sees of a photo: the upload after preprocessing.
+fn expected_upload(photo: &PathBuf) -> Vec<u8> {
+    let config = AzureConfig::default();
+    let preprocess = Preprocess { max_edge: config.max_edge, jpeg_quality: config.jpeg_quality };
+    prepare_upload(std::fs::read(photo).unwrap(), &preprocess).unwrap()
+}
+
+/// A small JPEG that is uploaded as it is, for tests about requests rather than photos.
+fn small_jpeg() -> Vec<u8> {
+    shaded_jpeg(128)
+}
+
+fn shaded_jpeg(shade: u8) -> Vec<u8> {
+    let image = RgbImage::from_fn(64, 48, |x, y| image::Rgb([(x * 4) as u8, (y * 5) as u8, shade]));
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, 90).encode_image(&image).unwrap();
+    jpeg
+}
+
+/// Distinct small JPEGs, so the mock gives each its own caption and tags.
+fn small_photos() -> Vec<Vec<u8>> {
+    (0..8).map(|number| shaded_jpeg(number * 30)).collect()
+}
+
+fn analyzer_for(server: &MockVisionServer, features: &[&str]) -> AzureAnalyzer {
+    analyzer_with(server.endpoint(), features, 5)
+}
+
+fn analyzer_with(endpoint: String, features: &[&str], max_attempts: u32) -> AzureAnalyzer {
+    AzureAnalyzer::new(AzureConfig {
+        endpoint,
+        key: "mock".to_string(),
+        features: features.iter().map(|feature| feature.to_string()).collect(),
+        requests_per_second: 1000.0,
+        max_attempts,
+        ..AzureConfig::default()
+    }).unwrap()
+}
+
+#[tokio::test]
+async fn analyzes_photos_deterministically() {
+    let server = MockVisionServer::start(MockOptions::default()).await.unwrap();
+    let analyzer = analyzer_for(&server, &["tags", "caption"]);
+    let photos = small_photos();
+
+    for upload in photos.iter().cloned() {
+        let analysis = analyzer.analyze_bytes(upload.clone()).await.unwrap();
+        let (caption, tags) = mock_analysis(&upload);
+        assert_eq!(analysis.caption, caption);
+        assert_eq!(analysis.tags.len(), tags.len());
+        assert_eq!(analysis.top_tags(1), vec![tags[0].0.clone()]);
+        assert!(analysis.lines.is_empty());
+
+        let again = analyzer.analyze_bytes(upload).await.unwrap();
+        assert_eq!(again.caption, analysis.caption);
+    }
+    assert_eq!(server.requests(), photos.len() * 2);
+}
+
+#[tokio::test]
+async fn photos_are_prepared_before_upload() {
+    let server = MockVisionServer::start(MockOptions::default()).await.unwrap();
+    let analyzer = analyzer_for(&server, &["tags", "caption"]);
+    let photo = smallest_test_photo();
+    let expected = {
+        let photo = photo.clone();
+        tokio::task::spawn_blocking(move || expected_upload(&photo))
+    };
+    let (analysis, expected) = tokio::join!(analyzer.analyze_path(photo.to_str().unwrap()), expected);
+    let expected = expected.unwrap();
+    assert_ne!(expected, std::fs::read(&photo).unwrap());
+    assert_eq!(analysis.unwrap().caption, mock_analysis(&expected).0);
+}
+
+#[tokio::test]
+async fn returns_text_when_read_is_requested() {
+    let server = MockVisionServer::start(MockOptions::default()).await.unwrap();
+    let analyzer = analyzer_for(&server, &["tags", "caption", "read"]);
+    let analysis = analyzer.analyze_bytes(small_jpeg()).await.unwrap();
+    assert_eq!(analysis.lines.len(), 1);
+    assert!(analysis.lines[0].starts_with("PHOTO "));
+}
+
+#[tokio::test]
+async fn returns_partial_results_for_partial_features() {
+    let server = MockVisionServer::start(MockOptions::default()).await.unwrap();
+    let analyzer = analyzer_for(&server, &["tags"]);
+    let analysis = analyzer.analyze_bytes(small_jpeg()).await.unwrap();
+    assert_eq!(analysis.caption, "");
+    assert_eq!(analysis.tags.len(), mock_analysis(&small_jpeg()).1.len());
+}
+
+#[tokio::test]
+async fn responses_without_results_are_errors() {
+    let server = MockVisionServer::start(MockOptions::default()).await.unwrap();
+    let analyzer = analyzer_for(&server, &["people"]);
+    assert!(matches!(analyzer.analyze_bytes(small_jpeg()).await.unwrap_err(), AnalyzeError::MissingField(_)));
+}
+
+#[tokio::test]
+async fn missing_files_are_io_errors() {
+    let server = MockVisionServer::start(MockOptions::default()).await.unwrap();
+    let analyzer = analyzer_for(&server, &["tags", "caption"]);
+    assert!(matches!(analyzer.analyze_path("no-such-photo.jpg").await.unwrap_err(), AnalyzeError::Io(_)));
+    assert_eq!(server.requests(), 0);
+}
+
+#[tokio::test]
+async fn returns_objects_and_dense_captions_with_relative_boxes() {
+    let server = MockVisionServer::start(MockOptions::default()).await.unwrap();
+    let analyzer = analyzer_for(&server, &["tags", "caption", "objects", "denseCaptions"]);
+    let upload = small_jpeg();
+    let analysis = analyzer.analyze_bytes(upload.clone()).await.unwrap();
+
+    let (width, height) = image::load_from_memory(&upload).unwrap().dimensions();
+    let (x, y, w, h) = mock_object_box(&upload, width, height);
+    let (_, tags) = mock_analysis(&upload);
+    assert_eq!(analysis.objects.len(), 1);
+    let object = &analysis.objects[0];
+    assert_eq!(object.name, tags[0].0);
+    assert!((object.bounds.x - x as f64 / width as f64).abs() < 1e-9);
+    assert!((object.bounds.y - y as f64 / height as f64).abs() < 1e-9);
+    assert!((object.bounds.width - w as f64 / width as f64).abs() < 1e-9);
+    assert!((object.bounds.height - h as f64 / height as f64).abs() < 1e-9);
+
+    assert_eq!(analysis.dense_captions.len(), 2);
+    assert_eq!(analysis.dense_captions[0].bounds.width, 1.0);
+    // The object's name is already a tag, so it is not listed twice.
+    assert_eq!(analysis.searchable_tags().len(), tags.len());
+}
+
+#[tokio::test]
+async fn throttled_and_failed_requests_are_retried() {
+    let server = MockVisionServer::start(MockOptions {
+        throttle_every: 2,
+        fail_every: 3,
+        retry_after: 0,
+        ..MockOptions::default()
+    }).await.unwrap();
+    let clock = Arc::new(VirtualClock::new());
+    let analyzer = analyzer_with(server.endpoint(), &["tags", "caption"], 10)
+        .with_backoff(Duration::from_millis(10))
+        .with_clock(clock.clone());
+
+    let outcomes = futures::future::join_all((0..6).map(|_| analyzer.analyze_bytes(small_jpeg()))).await;
+    assert!(outcomes.iter().all(|outcome| outcome.is_ok()));
+    assert!(server.requests() > 6);
+    let usage = analyzer.usage();
+    assert_eq!(usage.requests as usize, server.requests());
+    assert_eq!(usage.successes, 6);
+    assert_eq!(usage.failures, usage.requests - 6);
+    assert!(analyzer.rate() < 1000.0);
+    // The failed attempts backed off, on the virtual clock only.
+    assert!(clock.elapsed() > Duration::ZERO);
+}
+
+#[tokio::test]
+async fn retry_after_is_respected() {
+    let server = MockVisionServer::start(MockOptions {
+        throttle_every: 2,
+        retry_after: 1,
+        ..MockOptions::default()
+    }).await.unwrap();
+    let clock = Arc::new(VirtualClock::new());
+    let analyzer = analyzer_for(&server, &["tags", "caption"]).with_clock(clock.clone());
+
+    let start = Instant::now();
+    analyzer.analyze_bytes(small_jpeg()).await.unwrap();
+    analyzer.analyze_bytes(small_jpeg()).await.unwrap();
+    assert!(clock.elapsed() >= Duration::from_secs(1));
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(server.requests(), 3);
+}
+
+#[tokio::test]
+async fn gives_up_after_max_attempts() {
+    let server = MockVisionServer::start(MockOptions {
+        throttle_every: 1,
+        retry_after: 0,
+        ..MockOptions::default()
+    }).await.unwrap();
+    let analyzer = analyzer_with(server.endpoint(), &["tags", "caption"], 3).with_clock(Arc::new(VirtualClock::new()));
+
+    match analyzer.analyze_bytes(small_jpeg()).await.unwrap_err() {
+        AnalyzeError::GaveUp { attempts, last } => {
+            assert_eq!(attempts, 3);
+            assert_eq!(last.status(), Some(429));
+        }
+        other => panic!("unexpected error: {}", other),
+    }
+    assert_eq!(server.requests(), 3);
+}
+
+/// Lets a fixed number of requests through.
+struct Allowance(AtomicUsize);
+
+impl RequestGate for Allowance {
+    fn reserve(&self) -> Result<(), String> {
+        self.0.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| left.checked_sub(1))
+            .map(|_| ())
+            .map_err(|_| "allowance used up".to_string())
+    }
+}
+
+#[tokio::test]
+async fn retries_stop_when_the_gate_refuses() {
+    let server = MockVisionServer::start(MockOptions {
+        throttle_every: 1,
+        retry_after: 0,
+        ..MockOptions::default()
+    }).await.unwrap();
+    let analyzer = analyzer_with(server.endpoint(), &["tags", "caption"], 5).with_clock(Arc::new(VirtualClock::new()));
+    let gate = Allowance(AtomicUsize::new(2));
+
+    match analyzer.analyze_gated(small_jpeg(), &gate).await.unwrap_err() {
+        AnalyzeError::Refused(reason) => assert_eq!(reason, "allowance used up"),
+        other => panic!("unexpected error: {}", other),
+    }
+    assert_eq!(server.requests(), 2);
+    assert_eq!(analyzer.usage().requests, 2);
+}
+
+#[tokio::test]
+async fn permanent_errors_are_not_retried() {
+    let server = MockVisionServer::start(MockOptions::default()).await.unwrap();
+    let analyzer = analyzer_with(format!("{}/missing", server.endpoint()), &["tags", "caption"], 5);
+
+    match analyzer.analyze_bytes(small_jpeg()).await.unwrap_err() {
+        AnalyzeError::Status { status, error } => {
+            assert_eq!(status, 404);
+            assert_eq!(error.unwrap().code, "NotFound");
+        }
+        other => panic!("unexpected error: {}", other),
+    }
+    assert_eq!(server.requests(), 1);
+}
+
+#[tokio::test]
+async fn latency_delays_responses() {
+    let server = MockVisionServer::start(MockOptions {
+        latency: Duration::from_millis(200),
+        ..MockOptions::default()
+    }).await.unwrap();
+    let analyzer = analyzer_for(&server, &["tags", "caption"]);
+
+    let start = Instant::now();
+    analyzer.analyze_bytes(small_jpeg()).await.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(200));
+}
+
+#[tokio::test]
+async fn missing_key_is_rejected() {
+    let server = MockVisionServer::start(MockOptions::default()).await.unwrap();
+    let config = AzureConfig {
+        endpoint: server.endpoint(),
+        ..AzureConfig::default()
+    };
+    assert!(AzureAnalyzer::new(config).is_err());
+}
@@ -0,0 +1,64 @@
+use std::io::Cursor;
+use image::{DynamicImage, GenericImageView, ImageFormat, RgbImage};
+use img_azure::preprocess::{prepare_upload, Preprocess, PASS_THROUGH_BYTES};
+
+const OPTIONS: Preprocess = Preprocess { max_edge: 256, jpeg_quality: 85 };
+
+fn encode(image: &DynamicImage, format: ImageFormat) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    image.write_to(&mut Cursor::new(&mut bytes), format).unwrap();
+    bytes
+}
+
+fn gradient(width: u32, height: u32) -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| image::Rgb([(x % 256) as u8, (y % 256) as u8, 128])))
+}
+
+/// Inserts an EXIF segment with the given orientation right after the JPEG start marker.
+fn with_orientation(jpeg: &[u8], orientation: u16) -> Vec<u8> {
+    let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+    exif.extend_from_slice(&orientation.to_be_bytes());
+    exif.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+    let mut bytes = jpeg[..2].to_vec();
+    bytes.extend_from_slice(&[0xFF, 0xE1]);
+    bytes.extend_from_slice(&((exif.len() + 2) as u16).to_be_bytes());
+    bytes.extend_from_slice(&exif);
+    bytes.extend_from_slice(&jpeg[2..]);
+    bytes
+}
+
+fn dimensions(bytes: &[u8]) -> (u32, u32) {
+    image::load_from_memory_with_format(bytes, ImageFormat::Jpeg).unwrap().dimensions()
+}
+
+#[test]
+fn large_images_are_scaled_to_the_maximum_edge() {
+    let upload = prepare_upload(encode(&gradient(1000, 500), ImageFormat::Png), &OPTIONS).unwrap();
+    assert_eq!(dimensions(&upload), (256, 128));
+}
+
+#[test]
+fn exif_orientation_is_applied() {
+    let jpeg = encode(&gradient(200, 100), ImageFormat::Jpeg);
+    let upload = prepare_upload(with_orientation(&jpeg, 6), &OPTIONS).unwrap();
+    assert_eq!(dimensions(&upload), (100, 200));
+}
+
+#[test]
+fn small_upright_jpegs_are_uploaded_unchanged() {
+    let jpeg = encode(&gradient(200, 100), ImageFormat::Jpeg);
+    assert!(jpeg.len() <= PASS_THROUGH_BYTES);
+    assert_eq!(prepare_upload(jpeg.clone(), &OPTIONS).unwrap(), jpeg);
+}
+
+#[test]
+fn other_formats_are_re_encoded_as_jpeg() {
+    let upload = prepare_upload(encode(&gradient(100, 80), ImageFormat::Png), &OPTIONS).unwrap();
+    assert_eq!(image::guess_format(&upload).unwrap(), ImageFormat::Jpeg);
+    assert_eq!(dimensions(&upload), (100, 80));
+}
+
+#[test]
+fn unreadable_images_are_errors() {
+    assert!(prepare_upload(b"not an image".to_vec(), &OPTIONS).is_err());
+}
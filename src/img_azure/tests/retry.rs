@@ -0,0 +1,71 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use img_azure::retry::{classify_status, retry_after, AdaptiveLimiter, Clock, Failure, RetryPolicy, VirtualClock};
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::StatusCode;
+
+#[test]
+fn backoff_grows_and_is_capped() {
+    let policy = RetryPolicy {
+        max_attempts: 10,
+        base_delay: Duration::from_millis(100),
+        max_delay: Duration::from_secs(1),
+    };
+    for attempt in 1..10 {
+        let ceiling = Duration::from_millis(100 * (1 << (attempt - 1))).min(Duration::from_secs(1));
+        assert!(policy.backoff(attempt) <= ceiling);
+    }
+    assert_eq!(policy.delay(1, Some(Duration::from_secs(5))), Duration::from_secs(1));
+}
+
+#[test]
+fn statuses_are_classified() {
+    let mut headers = HeaderMap::new();
+    assert_eq!(classify_status(StatusCode::TOO_MANY_REQUESTS, &headers), Failure::Throttled { retry_after: None });
+    assert_eq!(classify_status(StatusCode::BAD_GATEWAY, &headers), Failure::Transient { retry_after: None });
+    assert_eq!(classify_status(StatusCode::UNAUTHORIZED, &headers), Failure::Permanent);
+    assert_eq!(classify_status(StatusCode::BAD_REQUEST, &headers), Failure::Permanent);
+
+    headers.insert("Retry-After", HeaderValue::from_static("2"));
+    assert_eq!(classify_status(StatusCode::SERVICE_UNAVAILABLE, &headers),
+               Failure::Throttled { retry_after: Some(Duration::from_secs(2)) });
+    headers.insert("retry-after-ms", HeaderValue::from_static("250"));
+    assert_eq!(retry_after(&headers), Some(Duration::from_millis(250)));
+}
+
+#[tokio::test]
+async fn limiter_slows_down_when_throttled_and_recovers() {
+    let clock = Arc::new(VirtualClock::new());
+    let limiter = AdaptiveLimiter::with_clock(100.0, clock.clone());
+    limiter.on_throttled(None);
+    limiter.on_throttled(None);
+    assert_eq!(limiter.rate(), 25.0);
+    for _ in 0..100 {
+        limiter.on_success();
+    }
+    assert_eq!(limiter.rate(), 100.0);
+
+    limiter.on_throttled(Some(Duration::from_millis(300)));
+    limiter.until_ready().await;
+    assert!(clock.elapsed() >= Duration::from_millis(300));
+}
+
+#[tokio::test]
+async fn limiter_spaces_requests_at_its_rate() {
+    let clock = Arc::new(VirtualClock::new());
+    let limiter = AdaptiveLimiter::with_clock(10.0, clock.clone());
+    for _ in 0..11 {
+        limiter.until_ready().await;
+    }
+    assert_eq!(clock.elapsed(), Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn virtual_sleeps_return_at_once() {
+    let clock = VirtualClock::new();
+    let start = Instant::now();
+    clock.sleep(Duration::from_secs(60)).await;
+    clock.sleep_until(clock.now() - Duration::from_secs(1)).await;
+    assert_eq!(clock.elapsed(), Duration::from_secs(60));
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
@@ -68,6 +68,7 @@ mod config {
 async fn main() {
     let app_props = App::new();
     let app_props_arc = Arc::new(Mutex::new(app_props));
+    init_analyzer(&app_props_arc);
 
 
     let img = image::open(&Path::new("logo.png")).unwrap();
@@ -86,7 +87,9 @@ async fn main() {
     if let Ok(app_props_guard) = app_props_arc.lock() {
         if let Ok(mut db_guard) = app_props_guard.db.lock() {
             if let Some(db) = db_guard.take() {
-                db.close();
+                if let Err(e) = db.close() {
+                    println!("Error closing database: {:?}", e);
+                }
             }
         }
     };
@@ -1,75 +1,236 @@
-use std::cmp::Ordering;
 use std::collections::HashSet;
 use std::fs::File;
 use std::io::BufWriter;
-use std::num::NonZeroU32;
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
-use app_props::app::{App, SomeTrie};
+use app_props::app::{check_index_model, hnsw_min_vectors, load_term_stats, load_weighting, open_library, rebuild_vector_index, report_error, App, SomeTrie};
+use app_props::library::{is_valid_library_name, list_libraries};
+use app_props::usage::UsageMeter;
 use tokio;
 use dioxus::prelude::*;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicUsize;
+use futures::stream::{self, StreamExt};
 use arc_str::arc_str::ArcStr;
-use db::database::{Database, Save};
+use db::analysis::{ImageAnalysis, Region, RegionKind, Tag};
+use db::database::Database;
+use db::batch::{BatchWriter, PendingImage};
+use db::error::DbError;
 use db::semantic_vector::SemanticVec;
-use img_azure::azure_api::AzureResponse;
-use vectorization::Embedding;
+use db::text_search::{fuse_ranks, matching_lines, matching_objects};
+use db::usage::{today, ApiUsage};
+use vectorization::{Embedding, EmbeddingError};
+use vectorization::embedder::TextEmbedder;
+use vectorization::weighting::Weighting;
 use db::image::Image;
 use file_system::dir_walker::DirWalker;
-use governor::{Quota, RateLimiter};
-use img_azure::get_response_by_path;
+use file_system::hash::{content_hash, file_hash};
+use img_azure::analyzer::{Analysis, DetectedRegion, ImageAnalyzer, RequestGate, Unlimited};
+use img_azure::error::AnalyzeError;
 
+/// Number of tags shown under a search result and used for its vector.
+const TOP_TAGS: usize = 10;
 
-async fn search_images(dir: String, app: Arc<Mutex<App>>) -> Vec<(String, u32, f32)> {
-    let embeddings = app.lock().unwrap().embeddings.clone();
-    let db = app.lock().unwrap().db.clone();
-    let mut db = db.lock().unwrap();
-    let mut embeddings = embeddings.lock().unwrap();
+/// A found photo with what the vision service said about it.
+#[derive(Clone, Debug)]
+pub struct SearchResult {
+    pub path: String,
+    pub id: u32,
+    pub score: f32,
+    pub caption: String,
+    pub tags: Vec<String>,
+    /// Lines of text read from the photo that contain a word of the query.
+    pub text: Vec<String>,
+    /// Detected objects named in the query or the tag filter, drawn as boxes over the photo.
+    pub objects: Vec<Region>,
+}
 
-    let res = embeddings.average_vector(dir.as_str());
+/// How the Image Search page matches a query against indexed photos.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SearchMode {
+    /// Cosine similarity between query and image vectors.
+    Semantic,
+    /// BM25-ranked exact words from captions, tags and text in the photos.
+    Keyword,
+    /// Both lists merged with reciprocal rank fusion.
+    Hybrid,
+}
+
+impl SearchMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SearchMode::Semantic => "Semantic",
+            SearchMode::Keyword => "Keyword",
+            SearchMode::Hybrid => "Hybrid",
+        }
+    }
+}
+
+/// Number of tags listed in the Image Search sidebar.
+const FACET_TAGS: usize = 20;
+
+/// Tags every search result must carry, picked from the Image Search sidebar.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TagFilter {
+    pub tags: Vec<String>,
+    pub min_confidence: f64,
+}
+
+impl TagFilter {
+    pub fn new(min_confidence: f64) -> TagFilter {
+        TagFilter { tags: Vec::new(), min_confidence }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
+    /// Adds the tag if it is not selected yet, removes it otherwise.
+    pub fn toggle(&mut self, tag: &str) {
+        match self.tags.iter().position(|selected| selected == tag) {
+            Some(position) => {
+                self.tags.remove(position);
+            }
+            None => self.tags.push(tag.to_string()),
+        }
+    }
+}
+
+async fn search_images(dir: String, mode: SearchMode, filter: TagFilter, app: Arc<Mutex<App>>) -> Result<Vec<SearchResult>, DbError> {
+    let embedder = app.lock().unwrap().embedder.clone();
+    let db = app.lock().unwrap().db.clone();
+    let (index, top_k) = {
+        let app = app.lock().unwrap();
+        (app.vector_index.clone(), app.search_top_k.load(std::sync::atomic::Ordering::Relaxed))
+    };
+    let db = db.lock().unwrap();
+    let db = db.as_ref().ok_or(DbError::Closed)?;
+    let embedder = embedder.lock().unwrap();
 
-    let ids = db.as_mut().unwrap().select_all_images();
-    let mut results = Vec::new();
     let time = std::time::Instant::now();
-    for id in ids {
-        let mut vec = Vec::new();
-        {
-            let mut statement = db.as_mut().unwrap().connection.as_mut().unwrap().prepare("SELECT value FROM semantic_vectors WHERE image_id = ?1").unwrap();
-            let mut rows = statement.query(&[&id]).unwrap();
-            while let Some(row) = rows.next().unwrap() {
-                let value: f32 = row.get(0).unwrap();
-                vec.push(value);
+    let index = index.lock().unwrap();
+    let allowed: Option<HashSet<u32>> = if filter.is_empty() {
+        None
+    } else {
+        Some(db.select_images_with_tags(&filter.tags, filter.min_confidence)?.into_iter().collect())
+    };
+    // Hybrid search looks further down both lists so fusion has candidates to work with;
+    // a tag filter may drop most hits, so then every image is ranked.
+    let mut candidates = match (&allowed, index.as_ref()) {
+        (Some(_), Some(index)) => index.len().max(top_k),
+        _ if mode == SearchMode::Hybrid => top_k * 4,
+        _ => top_k,
+    };
+
+    let query_vector = if mode == SearchMode::Keyword || dir.trim().is_empty() {
+        None
+    } else {
+        match embedder.embed(dir.as_str()) {
+            Ok(res) => Some(res),
+            Err(e) => {
+                println!("Error: {}", e);
+                if mode == SearchMode::Semantic {
+                    return Ok(Vec::new());
+                }
+                None
             }
         }
-        {
-            let mut statement = db.as_mut().unwrap().connection.as_mut().unwrap().prepare("SELECT path FROM images WHERE id = ?1").unwrap();
-            let mut rows = statement.query(&[&id]).unwrap();
-            let path: String = rows.next().unwrap().unwrap().get(0).unwrap();
-            results.push((path, id, Embedding::cosine_similarity(&res, &vec)));
+    };
+    // Photos deleted from disk are hidden; `remove_missing_images` drops them from the library.
+    // When that leaves fewer than `top_k`, both lists are searched again twice as deep.
+    let found = loop {
+        let semantic_hits = match (&query_vector, index.as_ref()) {
+            (Some(query_vector), Some(index)) => index.search(query_vector, candidates),
+            _ => Vec::new(),
+        };
+        let keyword_hits = if mode == SearchMode::Semantic || dir.trim().is_empty() {
+            Vec::new()
+        } else {
+            db.keyword_search(&dir, candidates)?
+        };
+        let more = semantic_hits.len() == candidates || keyword_hits.len() == candidates;
+        let mut hits = match mode {
+            // Without a prompt the selected tags alone decide what is shown.
+            _ if dir.trim().is_empty() => match &allowed {
+                Some(allowed) => {
+                    let mut ids: Vec<u32> = allowed.iter().copied().collect();
+                    ids.sort();
+                    ids.into_iter().map(|id| (id, 1.0)).collect()
+                }
+                None => Vec::new(),
+            },
+            SearchMode::Semantic => semantic_hits,
+            SearchMode::Keyword => keyword_hits,
+            SearchMode::Hybrid => fuse_ranks(&[semantic_hits, keyword_hits], candidates),
+        };
+        if let Some(allowed) = &allowed {
+            hits.retain(|(id, _)| allowed.contains(id));
         }
-    }
+        let mut found = Vec::new();
+        for (id, value) in hits {
+            if found.len() == top_k {
+                break;
+            }
+            match db.select_image_path(id)? {
+                Some(path) if Path::new(&path).exists() => found.push((id, path, value)),
+                _ => {}
+            }
+        }
+        if found.len() >= top_k || !more {
+            break found;
+        }
+        candidates *= 2;
+    };
 
-    results.sort_by(|a, b| match b.2.partial_cmp(&a.2) {
-        None => { Ordering::Less }
-        Some(res) => { res }
+    let mut results = Vec::new();
+    for (id, path, value) in found {
+        let (caption, tags, text, objects) = match db.select_analysis(id)? {
+            Some(analysis) => {
+                let mut objects = matching_objects(&analysis.regions, &dir);
+                objects.extend(analysis.regions.iter()
+                    .filter(|region| region.kind == RegionKind::Object && filter.tags.contains(&region.name))
+                    .filter(|region| !objects.iter().any(|object| object.name == region.name))
+                    .cloned()
+                    .collect::<Vec<Region>>());
+                (analysis.caption.clone(), analysis.top_tags(TOP_TAGS), matching_lines(&analysis.lines, &dir), objects)
+            }
+            None => (String::new(), Vec::new(), Vec::new(), Vec::new()),
+        };
+        results.push(SearchResult {
+            path,
+            id,
+            score: value,
+            caption,
+            tags,
+            text,
+            objects,
+        });
     }
-    );
     println!("Time: {:?}", time.elapsed());
-    results.iter().take(10).map(|(path, id, value)| (path.clone(), *id, *value)).collect()
+    Ok(results)
 }
 
 
-fn prepare_semantic_vec(embeddings: Arc<Mutex<Embedding>>, response: &mut AzureResponse, label_vec: &mut Vec<String>) -> Vec<f32> {
-    let semantic_vector_caption = embeddings.lock().unwrap().average_vector(&response.caption);
-    let semantic_vector_labels = embeddings.lock().unwrap().average_vector(&label_vec.join(" "));
-    let mut v = Vec::new();
-    for i in 0..semantic_vector_caption.len() {
-        v.push(semantic_vector_caption[i] + semantic_vector_labels[i]);
+/// Averages the caption and label vectors, using whichever of the two has known words.
+fn prepare_semantic_vec(embedder: Arc<Mutex<Box<dyn TextEmbedder + Send>>>, caption: &str, label_vec: &[String]) -> Result<Vec<f32>, EmbeddingError> {
+    let labels = label_vec.join(" ");
+    let embedder = embedder.lock().unwrap();
+    let vectors: Vec<Vec<f32>> = [caption, labels.as_str()].iter()
+        .filter_map(|text| embedder.embed(text).ok())
+        .collect();
+    if vectors.is_empty() {
+        return Err(EmbeddingError::OutOfVocabulary(format!("{} {}", caption, labels)));
+    }
+    let mut v = vec![0.0; embedder.dimensions()];
+    for vector in vectors.iter() {
+        for i in 0..v.len() {
+            v[i] += vector[i];
+        }
     }
     for i in 0..v.len() {
-        v[i] = v[i] / 2.0;
+        v[i] = v[i] / vectors.len() as f32;
     }
-    v
+    Ok(v)
 }
 
 async fn index_directory(dir: String, app: Arc<Mutex<App>>) {
@@ -189,7 +350,12 @@ pub fn file_search(cx: Scope<Arc<Mutex<App>>>) -> Element {
 pub fn image_search(cx: Scope<Arc<Mutex<App>>>) -> Element {
     let input_value = use_state(&cx, || "".to_string());
     let _found_files: &UseState<Vec<String>> = use_state(&cx, || Vec::new()); //uselles here only to satisfy hook order
-    let results_state: &UseState<Vec<(String, u32, f32)>> = use_state(&cx, || Vec::new());
+    let results_state: &UseState<Vec<SearchResult>> = use_state(&cx, || Vec::new());
+    let search_mode = use_state(&cx, || SearchMode::Hybrid);
+    let tag_filter = use_state(&cx, || TagFilter::new(0.5));
+    let min_confidence_value = use_state(&cx, || "0.5".to_string());
+    let search_error: &UseState<Option<String>> = use_state(&cx, || None);
+    let top_k_value = use_state(&cx, || cx.props.lock().unwrap().search_top_k.load(std::sync::atomic::Ordering::Relaxed).to_string());
     let app = cx.props.clone();
     let is_enabled = {
         let app = app.lock().unwrap();
@@ -243,6 +409,17 @@ pub fn image_search(cx: Scope<Arc<Mutex<App>>>) -> Element {
                         class: "col-12 d-flex justify-content-center align-items-center",
                         p { "Image Search" }
                     }
+                    div {
+                        class: "col-12",
+                        LibrarySwitcher {
+                            app: cx.props,
+                            on_switch: move |_| {
+                                results_state.set(Vec::new());
+                                search_error.set(None);
+                            },
+                            on_error: move |e| search_error.set(Some(e)),
+                        }
+                    }
                     div {
                         class: "col-12",
                         div {
@@ -257,6 +434,44 @@ pub fn image_search(cx: Scope<Arc<Mutex<App>>>) -> Element {
                             }
                         }
                     }
+                    div {
+                        class: "col-12",
+                        div {
+                            style: "display: flex; justify-content: center; align-items: center;",
+                            input {
+                                r#type: "number",
+                                min: "1",
+                                placeholder: "Results",
+                                value: "{top_k_value}",
+                                oninput: move |event| {
+                                    let input = &event.value;
+                                    if let Ok(top_k) = input.parse::<usize>() {
+                                        if top_k > 0 {
+                                            cx.props.lock().unwrap().search_top_k.store(top_k, std::sync::atomic::Ordering::Relaxed);
+                                        }
+                                    }
+                                    top_k_value.set(input.to_string());
+                                }
+                            }
+                        }
+                    }
+                    div {
+                        class: "col-12",
+                        div {
+                            style: "display: flex; justify-content: center; align-items: center;",
+                            p { format!("Mode: {}", search_mode.get().as_str()) }
+                        }
+                        div {
+                            style: "display: flex; justify-content: center; align-items: center;",
+                            for mode in [SearchMode::Semantic, SearchMode::Keyword, SearchMode::Hybrid] {
+                                div {
+                                    class: "menu-btn1",
+                                    onclick: move |_| search_mode.set(mode),
+                                    mode.as_str()
+                                }
+                            }
+                        }
+                    }
                     div {
                         class: "col-12",
                         div {
@@ -264,32 +479,105 @@ pub fn image_search(cx: Scope<Arc<Mutex<App>>>) -> Element {
                             div {
                                 class: "menu-btn1",
                                 onclick: move |_| {
-                                    let r = on_click_image_search(input_value.get().clone(), cx.props.clone());
-                                    results_state.set(r.clone());
+                                    match on_click_image_search(input_value.get().clone(), *search_mode.get(), tag_filter.get().clone(), cx.props.clone()) {
+                                        Ok(r) => {
+                                            results_state.set(r);
+                                            search_error.set(None);
+                                        }
+                                        Err(e) => search_error.set(Some(e.to_string())),
+                                    }
                                 },
                                 "Find Photo"
                             }
                         }
                     }
+                    search_error.get().as_ref().map(|error| rsx! {
+                        div {
+                            class: "col-12",
+                            div {
+                                style: "display: flex; justify-content: center; align-items: center; color: red;",
+                                p { "{error}" }
+                            }
+                        }
+                    })
+                }
+            }
+            div {
+                style: "float: left; width: 220px; padding: 10px;",
+                p { "Tags" }
+                input {
+                    r#type: "number",
+                    min: "0",
+                    max: "1",
+                    step: "0.05",
+                    placeholder: "Min confidence",
+                    value: "{min_confidence_value}",
+                    oninput: move |event| {
+                        let input = &event.value;
+                        if let Ok(min_confidence) = input.parse::<f64>() {
+                            let mut filter = tag_filter.get().clone();
+                            filter.min_confidence = min_confidence;
+                            tag_filter.set(filter);
+                        }
+                        min_confidence_value.set(input.to_string());
+                    }
+                }
+                for (tag, label) in tag_facets(cx.props, tag_filter.get()) {
+                    div {
+                        class: "menu-btn1",
+                        onclick: move |_| {
+                            let mut filter = tag_filter.get().clone();
+                            filter.toggle(&tag);
+                            match on_click_image_search(input_value.get().clone(), *search_mode.get(), filter.clone(), cx.props.clone()) {
+                                Ok(r) => {
+                                    results_state.set(r);
+                                    search_error.set(None);
+                                }
+                                Err(e) => search_error.set(Some(e.to_string())),
+                            }
+                            tag_filter.set(filter);
+                        },
+                        label
+                    }
                 }
             }
             div {
                 class: "file-container",
                 div {
                     class: "row",
-                    for (path, id, value) in results_state.get().iter() {
+                    for result in results_state.get().iter() {
                         div {
                             class: "col-12",
                             div {
                                 class: "file-p",
-                                img {
-                                    src: &**path,
-                                    width: "200",
-                                    height: "200"
+                                div {
+                                    style: "position: relative; width: 200px; height: 200px;",
+                                    img {
+                                        src: &*result.path,
+                                        width: "200",
+                                        height: "200"
+                                    }
+                                    for object in result.objects.iter() {
+                                        div {
+                                            title: "{object.name} ({object.confidence:.2})",
+                                            style: "position: absolute; left: {object.x * 100.0}%; top: {object.y * 100.0}%; width: {object.width * 100.0}%; height: {object.height * 100.0}%; border: 2px solid lime; box-sizing: border-box;",
+                                        }
+                                    }
                                 }
                                 div {
-                                    format!("Path: {}, Id: {}, Value: {}", path, id, value)
+                                    format!("Path: {}, Id: {}, Value: {}", result.path, result.id, result.score)
                                 }
+                                div {
+                                    format!("{}", result.caption)
+                                }
+                                div {
+                                    format!("Tags: {}", result.tags.join(", "))
+                                }
+                                (!result.text.is_empty()).then(|| rsx! {
+                                    div {
+                                        format!("Text: {}", result.text.join(" / "))
+                                    }
+                                })
                             }
                         }
                     }
@@ -461,6 +749,115 @@ pub fn file_index(cx: Scope<Arc<Mutex<App>>>) -> Element {
     })
 }
 
+/// Most common tags in the library as `(tag, label)` for the Image Search sidebar.
+/// Selected tags are marked and always listed.
+pub fn tag_facets(app: &Arc<Mutex<App>>, filter: &TagFilter) -> Vec<(String, String)> {
+    let (db, errors) = {
+        let app = app.lock().unwrap();
+        (app.db.clone(), app.errors.clone())
+    };
+    let db = db.lock().unwrap();
+    let mut counts = match db.as_ref().map(|db| db.select_tag_counts(filter.min_confidence, FACET_TAGS)) {
+        Some(Ok(counts)) => counts,
+        Some(Err(e)) => {
+            report_error(&errors, format!("Unable to load tags: {}", e));
+            Vec::new()
+        }
+        None => Vec::new(),
+    };
+    for tag in filter.tags.iter() {
+        if !counts.iter().any(|(name, _)| name == tag) {
+            counts.push((tag.clone(), 0));
+        }
+    }
+    counts.into_iter().map(|(tag, count)| {
+        let label = if filter.tags.contains(&tag) {
+            format!("[x] {} ({})", tag, count)
+        } else {
+            format!("{} ({})", tag, count)
+        };
+        (tag, label)
+    }).collect()
+}
+
+/// Known libraries as `(name, label)`, with the open one marked.
+pub fn library_buttons(current: &str) -> Vec<(String, String)> {
+    list_libraries().into_iter().map(|name| {
+        let label = if name == current { format!("[x] {}", name) } else { name.clone() };
+        (name, label)
+    }).collect()
+}
+
+#[derive(Props)]
+pub struct LibrarySwitcherProps<'a> {
+    app: &'a Arc<Mutex<App>>,
+    /// Called with the name of the library that was opened.
+    on_switch: EventHandler<'a, String>,
+    on_error: EventHandler<'a, String>,
+}
+
+/// The open library, a button per known library and a field to create a new one.
+#[allow(non_snake_case)]
+pub fn LibrarySwitcher<'a>(cx: Scope<'a, LibrarySwitcherProps<'a>>) -> Element<'a> {
+    let library_state = use_state(cx, || cx.props.app.lock().unwrap().library.clone());
+    let new_library_value = use_state(cx, || "".to_string());
+    cx.render(rsx! {
+        div {
+            style: "display: flex; justify-content: center; align-items: center;",
+            p { format!("Library: {}", library_state.get()) }
+        }
+        div {
+            style: "display: flex; justify-content: center; align-items: center;",
+            for (name, label) in library_buttons(library_state.get()) {
+                div {
+                    class: "menu-btn1",
+                    onclick: move |_| {
+                        match on_click_switch_library(name.clone(), cx.props.app) {
+                            Ok(()) => {
+                                library_state.set(name.clone());
+                                cx.props.on_switch.call(name.clone());
+                            }
+                            Err(e) => cx.props.on_error.call(e),
+                        }
+                    },
+                    "{label}"
+                }
+            }
+            input {
+                placeholder: "New library",
+                value: "{new_library_value}",
+                oninput: move |event| {
+                    let input = &event.value;
+                    new_library_value.set(input.to_string());
+                }
+            }
+            div {
+                class: "menu-btn1",
+                onclick: move |_| {
+                    let name = new_library_value.get().trim().to_string();
+                    match on_click_switch_library(name.clone(), cx.props.app) {
+                        Ok(()) => {
+                            library_state.set(name.clone());
+                            new_library_value.set(String::new());
+                            cx.props.on_switch.call(name);
+                        }
+                        Err(e) => cx.props.on_error.call(e),
+                    }
+                },
+                "Create"
+            }
+        }
+    })
+}
+
+/// Opens library `name`, creating it if it does not exist yet.
+pub fn on_click_switch_library(name: String, app: &Arc<Mutex<App>>) -> Result<(), String> {
+    if !is_valid_library_name(&name) {
+        return Err(format!("Invalid library name \"{}\": use letters, digits, - and _", name));
+    }
+    open_library(app, &name).map_err(|e| format!("Unable to open library {}: {}", name, e))
+}
+
 pub fn on_click_file_search(filename: String, app: &Arc<Mutex<App>>) -> Vec<String> {
     let mut app = app.lock().unwrap();
     let is_enabled = &mut app.is_prefix_search_enabled;
@@ -488,29 +885,54 @@ pub fn on_click_file_search(filename: String, app: &Arc<Mutex<App>>) -> Vec<Stri
     result
 }
 
-pub fn on_click_image_search(prompt: String, app: Arc<Mutex<App>>) -> Vec<(String, u32, f32)> {
+pub fn on_click_image_search(prompt: String, mode: SearchMode, filter: TagFilter, app: Arc<Mutex<App>>) -> Result<Vec<SearchResult>, DbError> {
     let mut r = Vec::new();
     let (tx, rx) = std::sync::mpsc::channel();
     tokio::spawn(async move {
-        let results = search_images(prompt, app).await;
+        let results = search_images(prompt, mode, filter, app).await;
         tx.send(results).unwrap();
     });
-    let results = rx.recv().unwrap();
-    for (path, id, value) in results.iter() {
+    let results = rx.recv().unwrap()?;
+    for result in results.iter() {
         let is_windows_os = cfg!(target_os = "windows");
         let src;
         if is_windows_os {
-            src = format!("/{}", path);
+            src = format!("/{}", result.path);
         } else {
-            src = format!("{}", path);
+            src = format!("{}", result.path);
         }
-        r.push((src.clone(), *id, *value));
+        r.push(SearchResult { path: src, ..result.clone() });
     }
-    r
+    Ok(r)
 }
 
 pub fn image_index(cx: Scope<Arc<Mutex<App>>>) -> Element {
     let input_value = use_state(&cx, || "".to_string());
+    let weighting_label = use_state(&cx, || weighting_description(cx.props));
+    let errors_state: &UseState<Vec<String>> = use_state(&cx, || Vec::new());
+    let batch_size_value = use_state(&cx, || cx.props.lock().unwrap().index_batch_size.load(std::sync::atomic::Ordering::Relaxed).to_string());
+    let usage_label = use_state(&cx, || usage_description(cx.props));
+    let hnsw_value = use_state(&cx, || hnsw_description(cx.props));
+    // Indexing runs in the background, so failures and requests it reports are polled into the page.
+    use_future(&cx, (), |_| {
+        let errors_state = errors_state.clone();
+        let usage_label = usage_label.clone();
+        let app = cx.props.clone();
+        let errors = cx.props.lock().unwrap().errors.clone();
+        async move {
+            loop {
+                let current = errors.lock().unwrap().clone();
+                if *errors_state.current() != current {
+                    errors_state.set(current);
+                }
+                let usage = usage_description(&app);
+                if *usage_label.current() != usage {
+                    usage_label.set(usage);
+                }
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            }
+        }
+    });
     let app = cx.props.clone();
     let is_enabled = {
         let app = app.lock().unwrap();
@@ -564,6 +986,14 @@ pub fn image_index(cx: Scope<Arc<Mutex<App>>>) -> Element {
                             style: "display: flex; justify-content: center; align-items: center;",
                                 p { "Image Indexing" }
                         }
+                        div {
+                            class: "col-12",
+                            LibrarySwitcher {
+                                app: cx.props,
+                                on_switch: move |_| weighting_label.set(weighting_description(cx.props)),
+                                on_error: move |e| report_error(&cx.props.lock().unwrap().errors, e),
+                            }
+                        }
                         div {
                             class: "col-12",
                             div {
@@ -593,8 +1023,135 @@ pub fn image_index(cx: Scope<Arc<Mutex<App>>>) -> Element {
                                     },
                                     "Index Photos"
                                 }
+                                div {
+                                    class: "menu-btn1",
+                                    onclick: move |_| {
+                                        let app_clone = cx.props.clone();
+                                        tokio::spawn(async move {
+                                            reembed_images(app_clone).await;
+                                        });
+                                    },
+                                    "Re-embed Photos"
+                                }
+                                div {
+                                    class: "menu-btn1",
+                                    onclick: move |_| {
+                                        let app_clone = cx.props.clone();
+                                        tokio::spawn(async move {
+                                            remove_missing_images(app_clone).await;
+                                        });
+                                    },
+                                    "Remove Missing Photos"
+                                }
+                            }
+                        }
+                        div {
+                            class: "col-12",
+                            div {
+                                style: "display: flex; justify-content: center; align-items: center;",
+                                p { "Batch size" }
+                                input {
+                                    r#type: "number",
+                                    min: "1",
+                                    placeholder: "Images per transaction",
+                                    value: "{batch_size_value}",
+                                    oninput: move |event| {
+                                        let input = &event.value;
+                                        if let Ok(batch_size) = input.parse::<usize>() {
+                                            if batch_size > 0 {
+                                                cx.props.lock().unwrap().index_batch_size.store(batch_size, std::sync::atomic::Ordering::Relaxed);
+                                            }
+                                        }
+                                        batch_size_value.set(input.to_string());
+                                    }
+                                }
+                            }
+                        }
+                        div {
+                            class: "col-12",
+                            div {
+                                style: "display: flex; justify-content: center; align-items: center;",
+                                p { "Vectors before HNSW" }
+                                input {
+                                    r#type: "number",
+                                    min: "1",
+                                    placeholder: "Images before approximate search",
+                                    value: "{hnsw_value}",
+                                    oninput: move |event| {
+                                        let input = &event.value;
+                                        if let Ok(min_vectors) = input.parse::<usize>() {
+                                            if min_vectors > 0 {
+                                                set_hnsw_min_vectors(cx.props, min_vectors);
+                                            }
+                                        }
+                                        hnsw_value.set(input.to_string());
+                                    }
+                                }
+                            }
+                        }
+                        div {
+                            class: "col-12",
+                            div {
+                                style: "display: flex; justify-content: center; align-items: center;",
+                                p { "{weighting_label}" }
+                            }
+                        }
+                        div {
+                            class: "col-12",
+                            div {
+                                style: "display: flex; justify-content: center; align-items: center;",
+                                for weighting in [Weighting::Uniform, Weighting::Idf, Weighting::Sif] {
+                                    div {
+                                        class: "menu-btn1",
+                                        onclick: move |_| {
+                                            set_weighting(cx.props, weighting);
+                                            weighting_label.set(weighting_description(cx.props));
+                                        },
+                                        format!("{}", weighting.as_str())
+                                    }
+                                }
+                                div {
+                                    class: "menu-btn1",
+                                    onclick: move |_| {
+                                        toggle_stop_words(cx.props);
+                                        weighting_label.set(weighting_description(cx.props));
+                                    },
+                                    "Stop words"
+                                }
+                            }
+                        }
+                        div {
+                            class: "col-12",
+                            div {
+                                style: "display: flex; justify-content: center; align-items: center;",
+                                p { "{usage_label}" }
+                            }
+                        }
+                        div {
+                            class: "col-12",
+                            for error in errors_state.get().iter().rev().take(10) {
+                                div {
+                                    style: "display: flex; justify-content: center; align-items: center; color: red;",
+                                    p { "{error}" }
+                                }
                             }
                         }
+                        (!errors_state.get().is_empty()).then(|| rsx! {
+                            div {
+                                class: "col-12",
+                                div {
+                                    style: "display: flex; justify-content: center; align-items: center;",
+                                    div {
+                                        class: "menu-btn1",
+                                        onclick: move |_| {
+                                            cx.props.lock().unwrap().errors.lock().unwrap().clear();
+                                            errors_state.set(Vec::new());
+                                        },
+                                        "Clear errors"
+                                    }
+                                }
+                            }
+                        })
                     }
                 }
             }
@@ -602,36 +1159,236 @@ pub fn image_index(cx: Scope<Arc<Mutex<App>>>) -> Element {
     }
 }
 
+/// Vision requests today, this month and in the last indexing run, against the budget.
+fn usage_description(app: &Arc<Mutex<App>>) -> String {
+    let app = app.lock().unwrap();
+    let db = app.usage_db.lock().unwrap();
+    let db = match db.as_ref() {
+        Some(db) => db,
+        None => return String::new(),
+    };
+    let describe = |usage: ApiUsage, limit: Option<u64>| {
+        let limit = limit.map(|limit| format!(" of {}", limit)).unwrap_or_default();
+        format!("{}{} ({} ok, {} failed)", usage.requests, limit, usage.successes, usage.failures)
+    };
+    let day = today();
+    match (db.select_usage(&day), db.select_usage(&day[..7]), db.select_last_run_usage()) {
+        (Ok(day_usage), Ok(month), Ok(last_run)) => format!(
+            "Vision requests today: {}, this month: {}, last run: {}",
+            describe(day_usage, app.budget.daily),
+            describe(month, app.budget.monthly),
+            last_run.map(|usage| describe(usage, None)).unwrap_or("none".to_string()),
+        ),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => e.to_string(),
+    }
+}
+
+fn hnsw_description(app: &Arc<Mutex<App>>) -> String {
+    let db = app.lock().unwrap().db.clone();
+    let db = db.lock().unwrap();
+    match db.as_ref().map(hnsw_min_vectors) {
+        Some(Ok(min_vectors)) => min_vectors.to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Stores the HNSW threshold for the library; it applies the next time the vector index is built.
+pub fn set_hnsw_min_vectors(app: &Arc<Mutex<App>>, min_vectors: usize) {
+    let (db, errors) = {
+        let app = app.lock().unwrap();
+        (app.db.clone(), app.errors.clone())
+    };
+    let mut db = db.lock().unwrap();
+    if let Some(db) = db.as_mut() {
+        if let Err(e) = db.set_setting("hnsw_min_vectors", &min_vectors.to_string()) {
+            report_error(&errors, format!("Unable to save the HNSW threshold: {}", e));
+        }
+    }
+}
+
+fn weighting_description(app: &Arc<Mutex<App>>) -> String {
+    let app = app.lock().unwrap();
+    let db = app.db.lock().unwrap();
+    let stop_words = match db.as_ref() {
+        Some(db) => match db.get_setting("stop_words") {
+            Ok(stop_words) => stop_words.unwrap_or("none".to_string()),
+            Err(e) => return e.to_string(),
+        },
+        None => "none".to_string(),
+    };
+    let mut embedder = app.embedder.lock().unwrap();
+    match embedder.word_vectors() {
+        Some(embeddings) => format!("Weighting: {}, stop words: {}", embeddings.weighting().as_str(), stop_words),
+        None => format!("Model {} does not use word weighting", embedder.model_id()),
+    }
+}
+
+/// Stores the weighting for the open index. The weighting is part of the model id, so
+/// vectors saved with the old one leave the index until they are re-embedded.
+pub fn set_weighting(app: &Arc<Mutex<App>>, weighting: Weighting) {
+    {
+        let app = app.lock().unwrap();
+        let mut db = app.db.lock().unwrap();
+        if let Some(db) = db.as_mut() {
+            if let Err(e) = db.set_setting("weighting", weighting.as_str()) {
+                report_error(&app.errors, format!("Unable to save weighting: {}", e));
+                return;
+            }
+        }
+        if let Some(embeddings) = app.embedder.lock().unwrap().word_vectors() {
+            embeddings.set_weighting(weighting);
+        }
+        println!("Weighting set to {}", weighting.as_str());
+    }
+    ask_to_reembed(app);
+}
+
+/// Switches the open index between the built-in English stop-word list and none.
+pub fn toggle_stop_words(app: &Arc<Mutex<App>>) {
+    {
+        let app = app.lock().unwrap();
+        let mut db = app.db.lock().unwrap();
+        let db = match db.as_mut() {
+            Some(db) => db,
+            None => return,
+        };
+        let current = match db.get_setting("stop_words") {
+            Ok(current) => current.unwrap_or("none".to_string()),
+            Err(e) => {
+                report_error(&app.errors, format!("Unable to read stop words setting: {}", e));
+                return;
+            }
+        };
+        let next = if current == "none" { "english" } else { "none" };
+        if let Err(e) = db.set_setting("stop_words", next) {
+            report_error(&app.errors, format!("Unable to save stop words setting: {}", e));
+            return;
+        }
+        if let Some(embeddings) = app.embedder.lock().unwrap().word_vectors() {
+            if let Err(e) = load_weighting(db, embeddings) {
+                report_error(&app.errors, format!("Unable to load weighting: {}", e));
+            }
+        };
+    }
+    ask_to_reembed(app);
+}
+
+/// Rebuilds the vector index for the current model id after a weighting change and asks
+/// for a re-embed when images were embedded with other settings.
+fn ask_to_reembed(app: &Arc<Mutex<App>>) {
+    {
+        let app = app.lock().unwrap();
+        let mut db = app.db.lock().unwrap();
+        let embedder = app.embedder.lock().unwrap();
+        let db = match db.as_mut() {
+            Some(db) => db,
+            None => return,
+        };
+        let model_id = embedder.model_id();
+        match check_index_model(db, &**embedder).and_then(|_| db.select_images_outside_model(&model_id)) {
+            Ok(images) if !images.is_empty() => report_error(&app.errors, format!(
+                "{} images were embedded with other weighting settings; re-embed them to search them with {}",
+                images.len(), model_id,
+            )),
+            Ok(_) => {}
+            Err(e) => report_error(&app.errors, format!("Unable to check the index model: {}", e)),
+        }
+    }
+    if let Err(e) = rebuild_vector_index(app) {
+        report_error(&app.lock().unwrap().errors, format!("Unable to build vector index: {}", e));
+    }
+}
+
 
 pub async fn index_images<'a>(dir: String, app: Arc<Mutex<App>>) {
     let walker = DirWalker::new(&dir).unwrap();
     let embeddings = {
         let app = app.lock().unwrap();
-        app.embeddings.clone()
+        app.embedder.clone()
     };
     let db = {
         let app = app.lock().unwrap();
         app.db.clone()
     };
+    let vector_index = {
+        let app = app.lock().unwrap();
+        app.vector_index.clone()
+    };
+    let errors = app.lock().unwrap().errors.clone();
+    let analyzer = match current_analyzer(&app) {
+        Some(analyzer) => analyzer,
+        None => {
+            report_error(&errors, "Indexing cancelled: no image analyzer is configured".to_string());
+            return;
+        }
+    };
+    {
+        let mut db = db.lock().unwrap();
+        let embedder = embeddings.lock().unwrap();
+        match check_index_model(db.as_mut().unwrap(), &**embedder) {
+            Ok(true) => {}
+            Ok(false) => {
+                report_error(&errors, "Indexing cancelled: the index uses a different embedding model".to_string());
+                return;
+            }
+            Err(e) => {
+                report_error(&errors, format!("Indexing cancelled: {}", e));
+                return;
+            }
+        }
+    }
+    let (budget, usage_db) = {
+        let app = app.lock().unwrap();
+        (app.budget, app.usage_db.clone())
+    };
+    let meter = match UsageMeter::start(analyzer.clone(), usage_db, budget, errors.clone()) {
+        Ok(meter) => Arc::new(meter),
+        Err(e) => {
+            report_error(&errors, format!("Indexing cancelled: {}", e));
+            return;
+        }
+    };
     let db_for_send = db.clone();
-    let limiter = Arc::new(RateLimiter::direct(
-        Quota::per_second(NonZeroU32::new(10).unwrap()),
-    ));
+    let batch_size = app.lock().unwrap().index_batch_size.load(std::sync::atomic::Ordering::Relaxed);
+    let writer = {
+        let embeddings = embeddings.clone();
+        let errors = errors.clone();
+        Arc::new(BatchWriter::new(db.clone(), batch_size, move |outcome| {
+            for (pending, e) in outcome.failed.iter() {
+                report_error(&errors, format!("{} ({})", e, pending.image.path));
+            }
+            if let Some(word_vectors) = embeddings.lock().unwrap().word_vectors() {
+                for pending in outcome.saved.iter() {
+                    word_vectors.add_document(&pending.tokens);
+                }
+            }
+            if let Some(index) = vector_index.lock().unwrap().as_mut() {
+                for pending in outcome.saved.iter() {
+                    if let Err(e) = index.insert(pending.image.id, &pending.image.semantic_vector.0) {
+                        report_error(&errors, format!("{} ({})", e, pending.image.path));
+                    }
+                }
+            }
+            println!("Saved {} images, {} failed", outcome.saved.len(), outcome.failed.len());
+        }))
+    };
 
     let db_for_send_clone = Arc::clone(&db_for_send);
     let embeddings_clone = Arc::clone(&embeddings);
-    let limiter_clone = Arc::clone(&limiter);
+    let writer_clone = Arc::clone(&writer);
+    let meter_clone = Arc::clone(&meter);
 
     walker.walk(move |path| {
+        let analyzer = analyzer.clone();
+        let meter = meter_clone.clone();
         let db_for_closure = Arc::clone(&db_for_send_clone);
         let embeddings = embeddings_clone.clone();
-        let limiter = limiter_clone.clone();
+        let writer = writer_clone.clone();
+        let errors = errors.clone();
         let path = path.to_owned();
         async move {
-            let db_for_closure = Arc::clone(&db_for_closure);
             let path_buf = PathBuf::from(path.clone());
             let embeddings = embeddings.clone();
-            let limiter = limiter.clone();
             let db_clone = db_for_closure.clone();
             if !path_buf.is_file() {
                 return;
@@ -640,51 +1397,366 @@ pub async fn index_images<'a>(dir: String, app: Arc<Mutex<App>>) {
                 return;
             }
 
-            if should_skip_image(db_clone, &path_buf) {
+            if should_skip_image(db_clone, &path_buf, &errors) {
                 return;
             }
 
-            let limiter = Arc::clone(&limiter);
-            let path_clone = path.to_string();
-
-
-            limiter.until_ready().await;
-            println!("indexing{}", path_clone);
-            let results = get_response_by_path(&path_clone).await;
-
-
-            let resp = results;
-            if resp.is_err() {
-                println!("Error: {:?}", resp.err().unwrap());
-                return;
-            }
-            let mut response = resp.unwrap();
-            let mut label_vec = Vec::new();
-            response.labels.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
-            for label in response.labels.iter().take(10) {
-                label_vec.push(label.name.clone());
-            }
+            println!("indexing{}", path);
+            let bytes = match tokio::fs::read(&path).await {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    report_error(&errors, format!("{} ({})", e, path));
+                    return;
+                }
+            };
+            let content_hash = content_hash(&bytes);
+            let model_id = embeddings.lock().unwrap().model_id();
+            let (cached, cached_vector) = match select_cached(&db_for_closure, &content_hash, &model_id) {
+                Ok(cached) => cached,
+                Err(e) => {
+                    report_error(&errors, format!("{} ({})", e, path));
+                    (None, None)
+                }
+            };
+            let analysis = match cached {
+                Some(analysis) => {
+                    println!("Reusing cached analysis for {}", path);
+                    analysis
+                }
+                None => {
+                    if !meter.allows_request() {
+                        return;
+                    }
+                    let analyzed = analyzer.analyze_gated(bytes, &*meter).await;
+                    meter.record();
+                    match analyzed {
+                        Ok(response) => analysis_from(0, response),
+                        // The pause was reported when the budget ran out.
+                        Err(AnalyzeError::Refused(_)) => return,
+                        Err(e) => {
+                            report_error(&errors, format!("{} ({})", e, path));
+                            return;
+                        }
+                    }
+                }
+            };
+            let label_vec = analysis.top_tags(TOP_TAGS);
+            let tokens = analysis_tokens(&analysis);
 
-            let semantic_vector = prepare_semantic_vec(embeddings.clone(), &mut response, &mut label_vec);
+            let semantic_vector = match cached_vector {
+                Some(vector) => vector,
+                None => match prepare_semantic_vec(embeddings.clone(), &analysis.caption, &label_vec) {
+                    Ok(semantic_vector) => semantic_vector,
+                    Err(e) => {
+                        report_error(&errors, format!("{} ({})", e, path));
+                        return;
+                    }
+                },
+            };
             let semantic_vector = SemanticVec::from_vec(semantic_vector);
 
             let mut image = Image::new(path.to_string(), path_buf.file_name().unwrap().to_str().unwrap().to_string());
             image.set_semantic_vector(semantic_vector);
+            image.set_content_hash(content_hash);
+            {
+                let embedder = embeddings.lock().unwrap();
+                image.set_model(embedder.model_id(), embedder.dimensions() as u32);
+            }
+
+            if let Err(e) = writer.send(PendingImage { image, analysis, tokens }) {
+                report_error(&errors, format!("{} ({})", e, path));
+            }
+        }
+    }).await;
+    // Commit the last partial batch before reporting that indexing is done.
+    if let Ok(writer) = Arc::try_unwrap(writer) {
+        let _ = tokio::task::spawn_blocking(move || writer.finish()).await;
+    }
+    meter.record();
+    if meter.is_paused() {
+        println!("Indexing paused, photos not analysed yet are indexed by the next run");
+    } else {
+        println!("Indexing finished");
+    }
+}
+
+/// Moves every image embedded with another model to the loaded one. Vectors are rebuilt
+/// from the stored caption and tags; only images without a stored or cached analysis are sent to
+/// the vision service again.
+pub async fn reembed_images(app: Arc<Mutex<App>>) {
+    let (embedder, db) = {
+        let app = app.lock().unwrap();
+        (app.embedder.clone(), app.db.clone())
+    };
+    let (model_id, dimensions) = {
+        let embedder = embedder.lock().unwrap();
+        (embedder.model_id(), embedder.dimensions())
+    };
+    let errors = app.lock().unwrap().errors.clone();
+    let analyzer = current_analyzer(&app);
+    let (budget, usage_db) = {
+        let app = app.lock().unwrap();
+        (app.budget, app.usage_db.clone())
+    };
+    let meter = match analyzer.clone().map(|analyzer| UsageMeter::start(analyzer, usage_db, budget, errors.clone())) {
+        Some(Ok(meter)) => Some(Arc::new(meter)),
+        Some(Err(e)) => {
+            report_error(&errors, format!("Re-embedding cancelled: {}", e));
+            return;
+        }
+        None => None,
+    };
+    let images = match db.lock().unwrap().as_ref().unwrap().select_images_outside_model(&model_id) {
+        Ok(images) => images,
+        Err(e) => {
+            report_error(&errors, format!("Re-embedding cancelled: {}", e));
+            return;
+        }
+    };
+    println!("Re-embedding {} images with {}", images.len(), model_id);
 
-            let mut db = db_for_closure.lock().unwrap();
-            let conn = db.as_mut().unwrap().connection.as_mut().unwrap();
-            match image.save(conn) {
-                Ok(_) => {}
+    let failed = Arc::new(AtomicUsize::new(0));
+    stream::iter(images).for_each_concurrent(10, |(id, path)| {
+        let embedder = embedder.clone();
+        let db = db.clone();
+        let failed = failed.clone();
+        let model_id = model_id.clone();
+        let errors = errors.clone();
+        let analyzer = analyzer.clone();
+        let meter = meter.clone();
+        async move {
+            let (content_hash, hashed_now) = ensure_content_hash(&db, id, &path, &errors);
+            let stored = db.lock().unwrap().as_ref().unwrap().select_analysis(id);
+            let analysis = match stored {
+                Ok(Some(analysis)) => {
+                    // Images indexed before hashes existed seed the cache on their first re-embed.
+                    if let (Some(content_hash), true) = (content_hash.as_deref(), hashed_now) {
+                        if let Err(e) = db.lock().unwrap().as_mut().unwrap().cache_analysis(content_hash, &analysis) {
+                            report_error(&errors, format!("{} ({})", e, path));
+                        }
+                    }
+                    analysis
+                }
+                Ok(None) => {
+                    if meter.as_ref().is_some_and(|meter| !meter.allows_request()) {
+                        failed.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        return;
+                    }
+                    let gate: &dyn RequestGate = match meter.as_ref() {
+                        Some(meter) => &**meter,
+                        None => &Unlimited,
+                    };
+                    let analyzed = analyze_again(&db, analyzer, gate, id, &path, content_hash.as_deref(), &errors).await;
+                    if let Some(meter) = meter.as_ref() {
+                        meter.record();
+                    }
+                    match analyzed {
+                        Ok(analysis) => analysis,
+                        Err(e) => {
+                            // A refusal was reported when the run was paused.
+                            if !meter.as_ref().is_some_and(|meter| meter.is_paused()) {
+                                report_error(&errors, format!("{} ({})", e, path));
+                            }
+                            failed.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                            return;
+                        }
+                    }
+                }
+                Err(e) => {
+                    report_error(&errors, format!("{} ({})", e, path));
+                    failed.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    return;
+                }
+            };
+            let label_vec = analysis.top_tags(TOP_TAGS);
+            let vector = match prepare_semantic_vec(embedder, &analysis.caption, &label_vec) {
+                Ok(vector) => vector,
                 Err(e) => {
-                    println!("Error: {:?}", e);
+                    report_error(&errors, format!("{} ({})", e, path));
+                    failed.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    return;
                 }
+            };
+            let mut db = db.lock().unwrap();
+            if let Err(e) = db.as_mut().unwrap().update_image_vector(id, &vector, &model_id, dimensions) {
+                report_error(&errors, format!("{} ({})", e, path));
+                failed.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
             }
         }
     }).await;
-    println!("Indexing finished");
+
+    let mut db = db.lock().unwrap();
+    if let Err(e) = db.as_mut().unwrap().set_index_model(&model_id, dimensions) {
+        report_error(&errors, format!("Unable to record index model: {}", e));
+    }
+    // Images analysed again above replaced their term counts.
+    if let Some(embeddings) = embedder.lock().unwrap().word_vectors() {
+        if let Err(e) = load_term_stats(db.as_ref().unwrap(), embeddings) {
+            report_error(&errors, format!("Unable to load term stats: {}", e));
+        }
+    }
+    drop(db);
+    if let Err(e) = rebuild_vector_index(&app) {
+        report_error(&errors, format!("Unable to build vector index: {}", e));
+    }
+    println!("Re-embedding finished, {} failed", failed.load(std::sync::atomic::Ordering::Relaxed));
+}
+
+/// Drops photos that are no longer on disk from the library, its term stats and the vector index.
+pub async fn remove_missing_images(app: Arc<Mutex<App>>) {
+    let (embedder, db, index, errors) = {
+        let app = app.lock().unwrap();
+        (app.embedder.clone(), app.db.clone(), app.vector_index.clone(), app.errors.clone())
+    };
+    let mut db = db.lock().unwrap();
+    let db = match db.as_mut() {
+        Some(db) => db,
+        None => return,
+    };
+    let images = match db.select_all_images() {
+        Ok(images) => images,
+        Err(e) => {
+            report_error(&errors, format!("Unable to list images: {}", e));
+            return;
+        }
+    };
+
+    let mut removed = 0;
+    for id in images {
+        let path = match db.select_image_path(id) {
+            Ok(Some(path)) => path,
+            Ok(None) => continue,
+            Err(e) => {
+                report_error(&errors, format!("{} (image {})", e, id));
+                continue;
+            }
+        };
+        if Path::new(&path).exists() {
+            continue;
+        }
+        if let Err(e) = db.delete_image(id) {
+            report_error(&errors, format!("{} ({})", e, path));
+            continue;
+        }
+        if let Some(index) = index.lock().unwrap().as_mut() {
+            index.remove(id);
+        }
+        removed += 1;
+    }
+
+    if removed > 0 {
+        if let Some(embeddings) = embedder.lock().unwrap().word_vectors() {
+            if let Err(e) = load_term_stats(db, embeddings) {
+                report_error(&errors, format!("Unable to load term stats: {}", e));
+            }
+        }
+    }
+    println!("Removed {} missing photos", removed);
 }
 
-pub fn should_skip_image(db: Arc<Mutex<Option<Database>>>, path: &PathBuf) -> bool {
+/// The stored content hash of an image, computing and storing it for images indexed
+/// before hashes existed. The flag tells whether the hash was computed just now.
+fn ensure_content_hash(db: &Arc<Mutex<Option<Database>>>, id: u32, path: &str, errors: &Arc<Mutex<Vec<String>>>) -> (Option<String>, bool) {
+    match db.lock().unwrap().as_ref().unwrap().select_content_hash(id) {
+        Ok(Some(content_hash)) => return (Some(content_hash), false),
+        Ok(None) => {}
+        Err(e) => {
+            report_error(errors, format!("{} ({})", e, path));
+            return (None, false);
+        }
+    }
+    let content_hash = match file_hash(path) {
+        Ok(content_hash) => content_hash,
+        Err(e) => {
+            report_error(errors, format!("{} ({})", e, path));
+            return (None, false);
+        }
+    };
+    if let Err(e) = db.lock().unwrap().as_mut().unwrap().set_content_hash(id, &content_hash) {
+        report_error(errors, format!("{} ({})", e, path));
+    }
+    (Some(content_hash), true)
+}
+
+/// Finds an analysis for an image that has none stored, from the cache when an image with
+/// the same contents was analysed before and from the vision service otherwise, and saves it.
+/// `gate` is asked before each request to the vision service.
+async fn analyze_again(db: &Arc<Mutex<Option<Database>>>, analyzer: Option<Arc<dyn ImageAnalyzer>>, gate: &dyn RequestGate,
+                       id: u32, path: &str, content_hash: Option<&str>, errors: &Arc<Mutex<Vec<String>>>) -> Result<ImageAnalysis, String> {
+    let cached = match content_hash {
+        Some(content_hash) => db.lock().unwrap().as_ref().unwrap().select_cached_analysis(content_hash).map_err(|e| e.to_string())?,
+        None => None,
+    };
+    let mut analysis = match cached {
+        Some(mut analysis) => {
+            analysis.image_id = id;
+            analysis
+        }
+        None => {
+            let analyzer = analyzer.ok_or_else(|| "No image analyzer is configured".to_string())?;
+            let image = tokio::fs::read(path).await.map_err(|e| e.to_string())?;
+            let response = analyzer.analyze_gated(image, gate).await.map_err(|e| e.to_string())?;
+            analysis_from(id, response)
+        }
+    };
+    let mut db = db.lock().unwrap();
+    let db = db.as_mut().unwrap();
+    if let Err(e) = db.save(&mut analysis) {
+        report_error(errors, format!("{} ({})", e, path));
+    }
+    if let Err(e) = db.set_image_terms(id, &analysis_tokens(&analysis)) {
+        report_error(errors, format!("{} ({})", e, path));
+    }
+    if let Some(content_hash) = content_hash {
+        if let Err(e) = db.cache_analysis(content_hash, &analysis) {
+            report_error(errors, format!("{} ({})", e, path));
+        }
+    }
+    Ok(analysis)
+}
+
+/// Words of the caption and top tags, as counted for IDF and SIF weights.
+fn analysis_tokens(analysis: &ImageAnalysis) -> Vec<String> {
+    Embedding::prepare_text(&format!("{} {}", analysis.caption, analysis.top_tags(TOP_TAGS).join(" ")))
+}
+
+/// What is stored of an analysis. Detected objects are also saved as tags, so they can be
+/// searched and filtered like any other tag.
+fn analysis_from(image_id: u32, response: Analysis) -> ImageAnalysis {
+    let tags = response.searchable_tags().into_iter().map(|tag| Tag { name: tag.name, confidence: tag.confidence }).collect();
+    let region = |kind: RegionKind, detected: &DetectedRegion| Region {
+        kind,
+        name: detected.name.clone(),
+        confidence: detected.confidence,
+        x: detected.bounds.x,
+        y: detected.bounds.y,
+        width: detected.bounds.width,
+        height: detected.bounds.height,
+    };
+    let regions = response.objects.iter().map(|object| region(RegionKind::Object, object))
+        .chain(response.dense_captions.iter().map(|caption| region(RegionKind::Caption, caption)))
+        .collect();
+    let mut analysis = ImageAnalysis::new(image_id, response.caption, tags);
+    analysis.set_lines(response.lines);
+    analysis.set_regions(regions);
+    analysis
+}
+
+/// The cached analysis of an image with these contents and, when one was computed with
+/// `model_id`, its vector.
+fn select_cached(db: &Arc<Mutex<Option<Database>>>, content_hash: &str, model_id: &str) -> Result<(Option<ImageAnalysis>, Option<Vec<f32>>), DbError> {
+    let db = db.lock().unwrap();
+    let db = db.as_ref().ok_or(DbError::Closed)?;
+    let analysis = db.select_cached_analysis(content_hash)?;
+    let vector = db.select_vector_by_hash(content_hash, model_id)?;
+    Ok((analysis, vector))
+}
+
+fn current_analyzer(app: &Arc<Mutex<App>>) -> Option<Arc<dyn ImageAnalyzer>> {
+    app.lock().unwrap().analyzer.clone()
+}
+
+pub fn should_skip_image(db: Arc<Mutex<Option<Database>>>, path: &PathBuf, errors: &Arc<Mutex<Vec<String>>>) -> bool {
     let mut flag = false;
     if path.is_file() {
         if DirWalker::is_image(path.to_str().unwrap()) {
@@ -695,14 +1767,20 @@ pub fn should_skip_image(db: Arc<Mutex<Option<Database>>>, path: &PathBuf) -> bo
                 return flag;
             }
             let (width, height) = res.unwrap();
-            if width < 50 || height < 50 || width > 16000 || height > 16000 {
+            if width < 50 || height < 50 {
                 flag = true;
                 println!("skip1 {}", path.to_str().unwrap());
                 return flag;
             } else {
                 let mut db = db.lock().unwrap();
                 let db = db.as_mut().unwrap();
-                flag = db.exists_image_by_path(path.to_str().unwrap()).unwrap();
+                flag = match db.exists_image_by_path(path.to_str().unwrap()) {
+                    Ok(exists) => exists,
+                    Err(e) => {
+                        report_error(errors, format!("{} ({})", e, path.display()));
+                        true
+                    }
+                };
                 if flag {
                     println!("skip2 {}", path.to_str().unwrap());
                 }
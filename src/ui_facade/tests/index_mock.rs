@@ -0,0 +1,313 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use app_props::app::App;
+use db::database::Database;
+use db::usage::{today, ApiUsage};
+use im::codecs::jpeg::JpegEncoder;
+use im::RgbImage;
+use img_azure::analyzer::AzureAnalyzer;
+use img_azure::config::{AzureConfig, CallBudget};
+use img_azure::mock_server::{mock_analysis, MockOptions, MockVisionServer};
+use img_azure::preprocess::{prepare_upload, Preprocess};
+use img_azure::retry::VirtualClock;
+use ui_facade::{index_images, on_click_image_search, SearchMode, TagFilter};
+use vectorization::{Embedding, EmbeddingError};
+use vectorization::embedder::TextEmbedder;
+use vectorization::index::{VectorIndex, HNSW_MIN_VECTORS};
+
+const DIMENSIONS: usize = 32;
+
+/// Bag-of-words vectors from hashed tokens, so tests need no word-vector file.
+struct HashEmbedder;
+
+impl TextEmbedder for HashEmbedder {
+    fn model_id(&self) -> String {
+        "test-hash".to_string()
+    }
+
+    fn dimensions(&self) -> usize {
+        DIMENSIONS
+    }
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>, EmbeddingError> {
+        let tokens = Embedding::prepare_text(text);
+        if tokens.is_empty() {
+            return Err(EmbeddingError::OutOfVocabulary(text.to_string()));
+        }
+        let mut vector = vec![0.0; DIMENSIONS];
+        for token in tokens {
+            let slot = token.bytes().fold(7usize, |hash, byte| hash.wrapping_mul(31).wrapping_add(byte as usize));
+            vector[slot % DIMENSIONS] += 1.0;
+        }
+        Ok(vector)
+    }
+}
+
+fn photos_test() -> String {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../photos_test").to_str().unwrap().to_string()
+}
+
+fn photo_count() -> usize {
+    std::fs::read_dir(photos_test()).unwrap().count()
+}
+
+const SMALL_PHOTOS: usize = 6;
+
+/// A directory of small, distinct JPEGs that are uploaded without scaling, for tests about
+/// indexing rather than about the photos. Scaling down `photos_test` takes seconds per photo.
+fn small_photos(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("file-search-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    for number in 0..SMALL_PHOTOS {
+        let shade = (number * 40) as u8;
+        let image = RgbImage::from_fn(80, 60, |x, y| im::Rgb([shade, (x * 3) as u8, (y * 4) as u8]));
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, 90).encode_image(&image).unwrap();
+        std::fs::write(dir.join(format!("photo-{}.jpg", number)), jpeg).unwrap();
+    }
+    dir.to_str().unwrap().to_string()
+}
+
+fn app_with(server: &MockVisionServer) -> Arc<Mutex<App>> {
+    let mut app = App::new();
+    app.embedder = Arc::new(Mutex::new(Box::new(HashEmbedder)));
+    app.db = Arc::new(Mutex::new(Some(Database::in_memory().unwrap())));
+    app.usage_db = Arc::new(Mutex::new(Some(Database::usage_in_memory().unwrap())));
+    app.vector_index = Arc::new(Mutex::new(Some(VectorIndex::build(DIMENSIONS, Vec::new(), HNSW_MIN_VECTORS))));
+    app.analyzer = Some(Arc::new(AzureAnalyzer::new(AzureConfig {
+        endpoint: server.endpoint(),
+        key: "mock".to_string(),
+        requests_per_second: 1000.0,
+        max_attempts: 10,
+        ..AzureConfig::default()
+    }).unwrap().with_clock(Arc::new(VirtualClock::new()))));
+    Arc::new(Mutex::new(app))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn indexes_photos_test_against_mock() {
+    let server = MockVisionServer::start(MockOptions::default()).await.unwrap();
+    let app = app_with(&server);
+
+    index_images(photos_test(), app.clone()).await;
+
+    let app = app.lock().unwrap();
+    assert!(app.errors.lock().unwrap().is_empty(), "{:?}", app.errors.lock().unwrap());
+    let db = app.db.lock().unwrap();
+    let db = db.as_ref().unwrap();
+    let ids = db.select_all_images().unwrap();
+    assert_eq!(ids.len(), photo_count());
+    assert_eq!(server.requests(), photo_count());
+    assert_eq!(app.vector_index.lock().unwrap().as_ref().unwrap().len(), photo_count());
+
+    for id in ids {
+        let path = db.select_image_path(id).unwrap().unwrap();
+        let analysis = db.select_analysis(id).unwrap().unwrap();
+        let config = AzureConfig::default();
+        let preprocess = Preprocess { max_edge: config.max_edge, jpeg_quality: config.jpeg_quality };
+        let (caption, tags) = mock_analysis(&prepare_upload(std::fs::read(&path).unwrap(), &preprocess).unwrap());
+        assert_eq!(analysis.caption, caption);
+        assert_eq!(analysis.tags.len(), tags.len());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn indexing_twice_skips_known_photos() {
+    let server = MockVisionServer::start(MockOptions::default()).await.unwrap();
+    let app = app_with(&server);
+    let photos = small_photos("twice");
+
+    index_images(photos.clone(), app.clone()).await;
+    index_images(photos.clone(), app.clone()).await;
+    std::fs::remove_dir_all(&photos).unwrap();
+
+    assert_eq!(server.requests(), SMALL_PHOTOS);
+    let app = app.lock().unwrap();
+    let images = app.db.lock().unwrap().as_ref().unwrap().select_all_images().unwrap();
+    assert_eq!(images.len(), SMALL_PHOTOS);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn throttled_photos_are_retried_until_saved() {
+    let server = MockVisionServer::start(MockOptions {
+        throttle_every: 4,
+        fail_every: 5,
+        retry_after: 0,
+        ..MockOptions::default()
+    }).await.unwrap();
+    let app = app_with(&server);
+    let photos = small_photos("throttled");
+
+    index_images(photos.clone(), app.clone()).await;
+    std::fs::remove_dir_all(&photos).unwrap();
+
+    let app = app.lock().unwrap();
+    assert!(app.errors.lock().unwrap().is_empty(), "{:?}", app.errors.lock().unwrap());
+    let db = app.db.lock().unwrap();
+    let db = db.as_ref().unwrap();
+    assert_eq!(db.select_all_images().unwrap().len(), SMALL_PHOTOS);
+    assert!(server.requests() > SMALL_PHOTOS);
+
+    let usage_db = app.usage_db.lock().unwrap();
+    let usage_db = usage_db.as_ref().unwrap();
+    let run = usage_db.select_last_run_usage().unwrap().unwrap();
+    assert_eq!(run.requests as usize, server.requests());
+    assert_eq!(run.successes as usize, SMALL_PHOTOS);
+    assert_eq!(run.failures, run.requests - run.successes);
+    assert_eq!(usage_db.select_usage(&today()).unwrap(), run);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn indexing_pauses_when_the_budget_is_used_up() {
+    let server = MockVisionServer::start(MockOptions::default()).await.unwrap();
+    let app = app_with(&server);
+    {
+        let mut app = app.lock().unwrap();
+        app.budget = CallBudget { daily: Some(5), monthly: None };
+        let mut db = app.usage_db.lock().unwrap();
+        let db = db.as_mut().unwrap();
+        let run = db.start_usage_run().unwrap();
+        db.add_usage(run, &today(), &ApiUsage { requests: 5, successes: 5, failures: 0 }).unwrap();
+    }
+
+    let photos = small_photos("budget");
+    index_images(photos.clone(), app.clone()).await;
+    std::fs::remove_dir_all(&photos).unwrap();
+
+    assert_eq!(server.requests(), 0);
+    let app = app.lock().unwrap();
+    let errors = app.errors.lock().unwrap();
+    assert_eq!(errors.iter().filter(|error| error.starts_with("Indexing paused")).count(), 1);
+    assert!(app.db.lock().unwrap().as_ref().unwrap().select_all_images().unwrap().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn retries_of_concurrent_photos_stay_within_the_budget() {
+    // Every request is throttled, so each photo would retry until it gives up.
+    let server = MockVisionServer::start(MockOptions {
+        throttle_every: 1,
+        retry_after: 0,
+        ..MockOptions::default()
+    }).await.unwrap();
+    let app = app_with(&server);
+    app.lock().unwrap().budget = CallBudget { daily: Some(4), monthly: None };
+
+    let photos = small_photos("retry-budget");
+    index_images(photos.clone(), app.clone()).await;
+    std::fs::remove_dir_all(&photos).unwrap();
+
+    assert_eq!(server.requests(), 4);
+    let app = app.lock().unwrap();
+    let usage = app.usage_db.lock().unwrap().as_ref().unwrap().select_usage(&today()).unwrap();
+    assert_eq!(usage.requests, 4);
+    assert_eq!(usage.failures, 4);
+    let errors = app.errors.lock().unwrap();
+    assert_eq!(errors.iter().filter(|error| error.starts_with("Indexing paused")).count(), 1, "{:?}", errors);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn copied_photos_reuse_cached_analysis() {
+    let server = MockVisionServer::start(MockOptions::default()).await.unwrap();
+    let app = app_with(&server);
+    let photos = small_photos("originals");
+    index_images(photos.clone(), app.clone()).await;
+
+    let copies = std::env::temp_dir().join(format!("file-search-copies-{}", std::process::id()));
+    std::fs::create_dir_all(&copies).unwrap();
+    let original = PathBuf::from(&photos).join("photo-0.jpg");
+    std::fs::copy(&original, copies.join("renamed.jpg")).unwrap();
+
+    index_images(copies.to_str().unwrap().to_string(), app.clone()).await;
+    std::fs::remove_dir_all(&copies).unwrap();
+
+    assert_eq!(server.requests(), SMALL_PHOTOS);
+    let app = app.lock().unwrap();
+    let db = app.db.lock().unwrap();
+    let db = db.as_ref().unwrap();
+    assert_eq!(db.select_all_images().unwrap().len(), SMALL_PHOTOS + 1);
+    let original = db.select_image_by_path(original.to_str().unwrap()).unwrap().unwrap();
+    let copy = db.select_image_by_path(copies.join("renamed.jpg").to_str().unwrap()).unwrap().unwrap();
+    assert_eq!(copy.content_hash, original.content_hash);
+    assert_eq!(copy.semantic_vector.0, original.semantic_vector.0);
+    std::fs::remove_dir_all(&photos).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn text_read_from_photos_is_searchable() {
+    let server = MockVisionServer::start(MockOptions::default()).await.unwrap();
+    let app = app_with(&server);
+    let photos = small_photos("text");
+    index_images(photos.clone(), app.clone()).await;
+
+    let (id, line) = {
+        let app = app.lock().unwrap();
+        let db = app.db.lock().unwrap();
+        let db = db.as_ref().unwrap();
+        let id = db.select_all_images().unwrap()[0];
+        (id, db.select_analysis(id).unwrap().unwrap().lines[0].clone())
+    };
+    // The mock reads "PHOTO <fingerprint>", and only the fingerprint is unique to the photo.
+    let fingerprint = line.split(' ').nth(1).unwrap().to_string();
+    let results = tokio::task::spawn_blocking(move || {
+        on_click_image_search(fingerprint, SearchMode::Keyword, TagFilter::new(0.0), app)
+    }).await.unwrap().unwrap();
+
+    std::fs::remove_dir_all(&photos).unwrap();
+
+    assert_eq!(results[0].id, id);
+    assert_eq!(results[0].text, vec![line]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn objects_matching_the_query_are_returned_with_boxes() {
+    let server = MockVisionServer::start(MockOptions::default()).await.unwrap();
+    let app = app_with(&server);
+    let photos = small_photos("objects");
+    index_images(photos.clone(), app.clone()).await;
+
+    let (id, object) = {
+        let app = app.lock().unwrap();
+        let db = app.db.lock().unwrap();
+        let db = db.as_ref().unwrap();
+        let id = db.select_all_images().unwrap()[0];
+        let analysis = db.select_analysis(id).unwrap().unwrap();
+        assert!(analysis.tags.iter().any(|tag| tag.name == analysis.regions[0].name));
+        (id, analysis.regions[0].clone())
+    };
+    let name = object.name.clone();
+    let results = tokio::task::spawn_blocking(move || {
+        on_click_image_search(name, SearchMode::Keyword, TagFilter::new(0.0), app)
+    }).await.unwrap().unwrap();
+    std::fs::remove_dir_all(&photos).unwrap();
+
+    let result = results.iter().find(|result| result.id == id).unwrap();
+    assert_eq!(result.objects.len(), 1);
+    assert_eq!(result.objects[0].name, object.name);
+    assert!(result.objects[0].x + result.objects[0].width <= 1.0);
+    assert!(result.objects[0].y + result.objects[0].height <= 1.0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn photos_missing_from_disk_do_not_shorten_the_results() {
+    let server = MockVisionServer::start(MockOptions::default()).await.unwrap();
+    let app = app_with(&server);
+    app.lock().unwrap().search_top_k.store(3, std::sync::atomic::Ordering::Relaxed);
+    let photos = small_photos("missing");
+    index_images(photos.clone(), app.clone()).await;
+    let search = |app: Arc<Mutex<App>>| tokio::task::spawn_blocking(move || {
+        // Every photo's text reads "PHOTO <fingerprint>".
+        on_click_image_search("photo".to_string(), SearchMode::Keyword, TagFilter::new(0.0), app)
+    });
+
+    let first = search(app.clone()).await.unwrap().unwrap();
+    for result in first.iter() {
+        std::fs::remove_file(&result.path).unwrap();
+    }
+    let second = search(app.clone()).await.unwrap().unwrap();
+    std::fs::remove_dir_all(&photos).unwrap();
+
+    assert_eq!(first.len(), 3);
+    assert_eq!(second.len(), 3);
+    assert!(second.iter().all(|result| first.iter().all(|removed| removed.id != result.id)));
+}
@@ -0,0 +1,53 @@
+use crate::fingerprint::short_hash;
+use crate::weighting::Weighting;
+use crate::{Embedding, EmbeddingError};
+
+/// A text-to-vector model usable for both search prompts and stored captions.
+pub trait TextEmbedder {
+    /// Identifies the model the vectors come from; vectors with different ids are not comparable.
+    fn model_id(&self) -> String;
+
+    fn dimensions(&self) -> usize;
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>, EmbeddingError>;
+
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>, EmbeddingError> {
+        texts.iter().map(|text| self.embed(text)).collect()
+    }
+
+    /// Gives access to word-vector specific settings (weighting, stop words, term counts).
+    fn word_vectors(&mut self) -> Option<&mut Embedding> {
+        None
+    }
+}
+
+impl TextEmbedder for Embedding {
+    /// The id of the loaded vectors plus the weighting and stop words, since both change
+    /// every sentence vector; vectors built with other settings need re-embedding.
+    fn model_id(&self) -> String {
+        let mut id = self.model_id.clone();
+        if self.weighting != Weighting::Uniform {
+            id.push('+');
+            id.push_str(self.weighting.as_str());
+        }
+        if !self.stop_words.is_empty() {
+            let mut words: Vec<&str> = self.stop_words.iter().map(|word| word.as_str()).collect();
+            words.sort();
+            id.push_str("+stop:");
+            id.push_str(&short_hash(words.join("\n").as_bytes()));
+        }
+        id
+    }
+
+    fn dimensions(&self) -> usize {
+        self.dims()
+    }
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>, EmbeddingError> {
+        self.average_vector(text)
+    }
+
+    fn word_vectors(&mut self) -> Option<&mut Embedding> {
+        Some(self)
+    }
+}
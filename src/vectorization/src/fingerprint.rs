@@ -0,0 +1,37 @@
+use std::fmt::Write;
+use sha2::{Digest, Sha256};
+
+/// First 8 bytes of the SHA-256 of `bytes` in hex, short enough to put in a model id.
+pub fn short_hash(bytes: &[u8]) -> String {
+    hex(&Sha256::digest(bytes)[..8])
+}
+
+const SAMPLES: usize = 64;
+const SAMPLE_LEN: usize = 64 * 1024;
+
+/// Short hash of the lengths of model files and 64 evenly spaced 64 KiB samples of each, which
+/// tells models apart without reading gigabytes of weights. Smaller files are hashed whole.
+pub fn sampled_hash(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for data in parts {
+        hasher.update((data.len() as u64).to_le_bytes());
+        if data.len() <= SAMPLES * SAMPLE_LEN {
+            hasher.update(data);
+            continue;
+        }
+        // The first sample starts the file and the last one ends it.
+        for sample in 0..SAMPLES {
+            let start = sample * (data.len() - SAMPLE_LEN) / (SAMPLES - 1);
+            hasher.update(&data[start..start + SAMPLE_LEN]);
+        }
+    }
+    hex(&hasher.finalize()[..8])
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hash = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hash, "{:02x}", byte);
+    }
+    hash
+}
@@ -0,0 +1,90 @@
+use std::path::Path;
+
+/// On-disk layouts understood by `Embedding::get_embeddings`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmbeddingFormat {
+    /// GloVe text: one `word v1 v2 ...` line per word, no header.
+    Glove,
+    /// word2vec / fastText `.vec` text: a `<words> <dims>` header followed by GloVe-style lines.
+    TextWithHeader,
+    /// word2vec binary: a `<words> <dims>` header followed by `word ` and raw little-endian floats.
+    Word2VecBinary,
+    /// finalfusion container, recognised by its `FiFu` magic.
+    FinalFusion,
+}
+
+const FINALFUSION_MAGIC: &[u8] = b"FiFu";
+
+impl EmbeddingFormat {
+    /// Recognises the format from the file contents, falling back to the extension only
+    /// when they are inconclusive, e.g. a header without a complete first record.
+    pub fn detect(path: &str, data: &[u8]) -> EmbeddingFormat {
+        if let Some(format) = Self::probe(data) {
+            return format;
+        }
+        let extension = Path::new(path).extension().and_then(|ext| ext.to_str());
+        match extension {
+            Some("bin") => EmbeddingFormat::Word2VecBinary,
+            Some("vec") => EmbeddingFormat::TextWithHeader,
+            Some("fifu") => EmbeddingFormat::FinalFusion,
+            _ => EmbeddingFormat::Glove,
+        }
+    }
+
+    fn probe(data: &[u8]) -> Option<EmbeddingFormat> {
+        if data.starts_with(FINALFUSION_MAGIC) {
+            return Some(EmbeddingFormat::FinalFusion);
+        }
+
+        let header_end = data.iter().position(|b| *b == b'\n').unwrap_or(data.len());
+        let dims = match Self::parse_header(&data[..header_end]) {
+            Some(dims) => dims,
+            None if Self::text_record(&data[..header_end]).is_some() => return Some(EmbeddingFormat::Glove),
+            None => return None,
+        };
+
+        // A text record is a word followed by `dims` printable numbers; a binary
+        // record is a word, a space and `4 * dims` raw bytes.
+        let body = data.get(header_end + 1..).unwrap_or(&[]);
+        let probe = &body[..body.len().min(dims * 32 + 256)];
+        let first_line = match probe.iter().position(|b| *b == b'\n') {
+            Some(end) => &probe[..end],
+            None => probe,
+        };
+        if Self::text_record(first_line) == Some(dims) {
+            return Some(EmbeddingFormat::TextWithHeader);
+        }
+        let word_end = body.iter().take(256).position(|b| *b == b' ')?;
+        if word_end > 0 && body.len() >= word_end + 1 + dims * 4 {
+            return Some(EmbeddingFormat::Word2VecBinary);
+        }
+        None
+    }
+
+    fn parse_header(line: &[u8]) -> Option<usize> {
+        let line = std::str::from_utf8(line).ok()?;
+        let mut parts = line.split_whitespace();
+        let _words: usize = parts.next()?.parse().ok()?;
+        let dims: usize = parts.next()?.parse().ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(dims)
+    }
+
+    /// The number of values in a `word v1 v2 ...` line, `None` when it is not one.
+    fn text_record(line: &[u8]) -> Option<usize> {
+        let line = std::str::from_utf8(line).ok()?;
+        let mut parts = line.split_whitespace();
+        parts.next()?;
+        let mut values = 0;
+        for part in parts {
+            part.parse::<f32>().ok()?;
+            values += 1;
+        }
+        if values == 0 {
+            return None;
+        }
+        Some(values)
+    }
+}
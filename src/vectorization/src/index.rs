@@ -0,0 +1,407 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
+
+/// Default for how many vectors a library needs before it gets an HNSW graph instead of a flat scan.
+pub const HNSW_MIN_VECTORS: usize = 50_000;
+
+/// The HNSW graph is rebuilt from its live vectors once more than 1 in this many nodes are
+/// tombstones, so replacing vectors does not grow it without limit.
+pub const HNSW_MAX_TOMBSTONES_PER: usize = 4;
+
+/// A vector whose length does not match the index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WrongDimensions {
+    pub id: u32,
+    pub found: usize,
+    pub expected: usize,
+}
+
+impl fmt::Display for WrongDimensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "vector of image {} has {} dims, the index has {}", self.id, self.found, self.expected)
+    }
+}
+
+/// Top-k cosine search over image vectors kept in memory.
+///
+/// Vectors are normalised on insert so similarity is a plain dot product.
+pub enum VectorIndex {
+    Flat(FlatIndex),
+    Hnsw(HnswIndex),
+}
+
+impl VectorIndex {
+    /// Builds a flat index, or an HNSW graph when there are at least `hnsw_min_vectors` items.
+    /// Items of the wrong length are left out; callers that need to report them check first.
+    pub fn build(dimensions: usize, items: Vec<(u32, Vec<f32>)>, hnsw_min_vectors: usize) -> VectorIndex {
+        let mut index = if items.len() >= hnsw_min_vectors {
+            VectorIndex::Hnsw(HnswIndex::new(dimensions))
+        } else {
+            VectorIndex::Flat(FlatIndex::new(dimensions))
+        };
+        for (id, vector) in items {
+            let _ = index.insert(id, &vector);
+        }
+        index
+    }
+
+    pub fn dimensions(&self) -> usize {
+        match self {
+            VectorIndex::Flat(index) => index.dimensions,
+            VectorIndex::Hnsw(index) => index.dimensions,
+        }
+    }
+
+    /// Adds or replaces the vector for `id`. Vectors of the wrong length are refused and
+    /// leave the index unchanged.
+    pub fn insert(&mut self, id: u32, vector: &[f32]) -> Result<(), WrongDimensions> {
+        if vector.len() != self.dimensions() {
+            return Err(WrongDimensions { id, found: vector.len(), expected: self.dimensions() });
+        }
+        match self {
+            VectorIndex::Flat(index) => index.insert(id, vector),
+            VectorIndex::Hnsw(index) => index.insert(id, vector),
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        match self {
+            VectorIndex::Flat(index) => index.remove(id),
+            VectorIndex::Hnsw(index) => index.remove(id),
+        }
+    }
+
+    /// Returns up to `k` `(id, cosine similarity)` pairs, best first.
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<(u32, f32)> {
+        if query.len() != self.dimensions() || k == 0 {
+            return Vec::new();
+        }
+        let query = normalized(query);
+        match self {
+            VectorIndex::Flat(index) => index.search(&query, k),
+            VectorIndex::Hnsw(index) => index.search(&query, k),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            VectorIndex::Flat(index) => index.positions.len(),
+            VectorIndex::Hnsw(index) => index.positions.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn normalized(vector: &[f32]) -> Vec<f32> {
+    let norm = dot(vector, vector).sqrt();
+    if norm == 0.0 {
+        return vector.to_vec();
+    }
+    vector.iter().map(|value| value / norm).collect()
+}
+
+/// Dot product over 8-wide chunks with independent accumulators, which the compiler
+/// turns into SIMD multiply-adds.
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    let mut sums = [0.0f32; 8];
+    let chunks_a = a.chunks_exact(8);
+    let chunks_b = b.chunks_exact(8);
+    let rest: f32 = chunks_a.remainder().iter().zip(chunks_b.remainder()).map(|(x, y)| x * y).sum();
+    for (x, y) in chunks_a.zip(chunks_b) {
+        for ((sum, x), y) in sums.iter_mut().zip(x).zip(y) {
+            *sum += x * y;
+        }
+    }
+    sums.iter().sum::<f32>() + rest
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Candidate(f32, usize);
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.partial_cmp(&other.0).unwrap_or(Ordering::Equal).then(self.1.cmp(&other.1))
+    }
+}
+
+/// Exhaustive scan over a contiguous block of normalised vectors.
+pub struct FlatIndex {
+    dimensions: usize,
+    ids: Vec<u32>,
+    vectors: Vec<f32>,
+    positions: HashMap<u32, usize>,
+}
+
+impl FlatIndex {
+    pub fn new(dimensions: usize) -> FlatIndex {
+        FlatIndex {
+            dimensions,
+            ids: Vec::new(),
+            vectors: Vec::new(),
+            positions: HashMap::new(),
+        }
+    }
+
+    fn insert(&mut self, id: u32, vector: &[f32]) {
+        let vector = normalized(vector);
+        match self.positions.get(&id) {
+            Some(&position) => {
+                self.vectors[position * self.dimensions..(position + 1) * self.dimensions].copy_from_slice(&vector);
+            }
+            None => {
+                self.positions.insert(id, self.ids.len());
+                self.ids.push(id);
+                self.vectors.extend_from_slice(&vector);
+            }
+        }
+    }
+
+    fn remove(&mut self, id: u32) {
+        let position = match self.positions.remove(&id) {
+            Some(position) => position,
+            None => return,
+        };
+        let last = self.ids.len() - 1;
+        if position != last {
+            let moved = self.ids[last];
+            self.ids[position] = moved;
+            self.vectors.copy_within(last * self.dimensions..(last + 1) * self.dimensions, position * self.dimensions);
+            self.positions.insert(moved, position);
+        }
+        self.ids.pop();
+        self.vectors.truncate(last * self.dimensions);
+    }
+
+    fn search(&self, query: &[f32], k: usize) -> Vec<(u32, f32)> {
+        let mut best: BinaryHeap<Reverse<Candidate>> = BinaryHeap::with_capacity(k + 1);
+        for (position, vector) in self.vectors.chunks_exact(self.dimensions).enumerate() {
+            best.push(Reverse(Candidate(dot(query, vector), position)));
+            if best.len() > k {
+                best.pop();
+            }
+        }
+        best.into_sorted_vec().into_iter()
+            .map(|Reverse(Candidate(score, position))| (self.ids[position], score))
+            .collect()
+    }
+}
+
+/// Hierarchical navigable small world graph for approximate search in large libraries.
+///
+/// Removed vectors stay in the graph as tombstones so it remains navigable; they are
+/// skipped in results, and the graph is rebuilt without them once there are too many.
+pub struct HnswIndex {
+    dimensions: usize,
+    m: usize,
+    ef_construction: usize,
+    ef_search: usize,
+    ids: Vec<u32>,
+    vectors: Vec<f32>,
+    links: Vec<Vec<Vec<usize>>>,
+    deleted: Vec<bool>,
+    tombstones: usize,
+    positions: HashMap<u32, usize>,
+    entry: Option<usize>,
+    max_level: usize,
+    seed: u64,
+}
+
+impl HnswIndex {
+    pub fn new(dimensions: usize) -> HnswIndex {
+        HnswIndex::with_params(dimensions, 16, 200, 64)
+    }
+
+    pub fn with_params(dimensions: usize, m: usize, ef_construction: usize, ef_search: usize) -> HnswIndex {
+        HnswIndex {
+            dimensions,
+            m,
+            ef_construction,
+            ef_search,
+            ids: Vec::new(),
+            vectors: Vec::new(),
+            links: Vec::new(),
+            deleted: Vec::new(),
+            tombstones: 0,
+            positions: HashMap::new(),
+            entry: None,
+            max_level: 0,
+            seed: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    /// Nodes in the graph, tombstones included.
+    pub fn node_count(&self) -> usize {
+        self.ids.len()
+    }
+
+    fn vector(&self, node: usize) -> &[f32] {
+        &self.vectors[node * self.dimensions..(node + 1) * self.dimensions]
+    }
+
+    fn random_level(&mut self) -> usize {
+        // xorshift64*, enough randomness for level assignment.
+        self.seed ^= self.seed >> 12;
+        self.seed ^= self.seed << 25;
+        self.seed ^= self.seed >> 27;
+        let value = self.seed.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        let uniform = ((value >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        (-uniform.ln() / (self.m as f64).ln()).floor() as usize
+    }
+
+    fn max_links(&self, layer: usize) -> usize {
+        if layer == 0 { self.m * 2 } else { self.m }
+    }
+
+    fn insert(&mut self, id: u32, vector: &[f32]) {
+        self.remove(id);
+        let vector = normalized(vector);
+        let node = self.ids.len();
+        let level = self.random_level();
+        self.ids.push(id);
+        self.vectors.extend_from_slice(&vector);
+        self.links.push(vec![Vec::new(); level + 1]);
+        self.deleted.push(false);
+        self.positions.insert(id, node);
+
+        let mut entry = match self.entry {
+            Some(entry) => entry,
+            None => {
+                self.entry = Some(node);
+                self.max_level = level;
+                return;
+            }
+        };
+
+        for layer in (level + 1..=self.max_level).rev() {
+            entry = self.search_layer(&vector, vec![entry], 1, layer, false)[0].1;
+        }
+
+        let mut entry_points = vec![entry];
+        for layer in (0..=level.min(self.max_level)).rev() {
+            let candidates = self.search_layer(&vector, entry_points.clone(), self.ef_construction, layer, false);
+            let neighbours: Vec<usize> = candidates.iter().take(self.m).map(|Candidate(_, n)| *n).collect();
+            self.links[node][layer] = neighbours.clone();
+            for neighbour in neighbours {
+                self.links[neighbour][layer].push(node);
+                if self.links[neighbour][layer].len() > self.max_links(layer) {
+                    self.prune(neighbour, layer);
+                }
+            }
+            entry_points = candidates.iter().map(|Candidate(_, n)| *n).collect();
+        }
+
+        if level > self.max_level {
+            self.max_level = level;
+            self.entry = Some(node);
+        }
+    }
+
+    fn prune(&mut self, node: usize, layer: usize) {
+        let base = self.vector(node).to_vec();
+        let mut scored: Vec<Candidate> = self.links[node][layer].iter()
+            .map(|&n| Candidate(dot(&base, self.vector(n)), n))
+            .collect();
+        scored.sort_by(|a, b| b.cmp(a));
+        scored.truncate(self.max_links(layer));
+        self.links[node][layer] = scored.into_iter().map(|Candidate(_, n)| n).collect();
+    }
+
+    fn remove(&mut self, id: u32) {
+        if let Some(node) = self.positions.remove(&id) {
+            self.deleted[node] = true;
+            self.tombstones += 1;
+            if self.tombstones * HNSW_MAX_TOMBSTONES_PER > self.ids.len() {
+                self.rebuild();
+            }
+        }
+    }
+
+    /// Rebuilds the graph from the live vectors, dropping every tombstone.
+    fn rebuild(&mut self) {
+        // In insertion order, so the graph does not depend on hash map order.
+        let mut live: Vec<(usize, u32)> = self.positions.iter().map(|(&id, &node)| (node, id)).collect();
+        live.sort_unstable();
+        let live: Vec<(u32, Vec<f32>)> = live.into_iter().map(|(node, id)| (id, self.vector(node).to_vec())).collect();
+        let mut rebuilt = HnswIndex::with_params(self.dimensions, self.m, self.ef_construction, self.ef_search);
+        rebuilt.seed = self.seed;
+        for (id, vector) in live {
+            rebuilt.insert(id, &vector);
+        }
+        *self = rebuilt;
+    }
+
+    /// Best-first search on one layer, returning up to `ef` candidates, best first. Tombstones
+    /// are followed but, with `skip_deleted`, not returned, so they do not take places in `ef`.
+    fn search_layer(&self, query: &[f32], entry_points: Vec<usize>, ef: usize, layer: usize, skip_deleted: bool) -> Vec<Candidate> {
+        let mut visited = HashSet::new();
+        let mut candidates = BinaryHeap::new();
+        let mut results: BinaryHeap<Reverse<Candidate>> = BinaryHeap::new();
+        for entry in entry_points {
+            if visited.insert(entry) {
+                let candidate = Candidate(dot(query, self.vector(entry)), entry);
+                candidates.push(candidate);
+                if !(skip_deleted && self.deleted[entry]) {
+                    results.push(Reverse(candidate));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        while let Some(Candidate(score, node)) = candidates.pop() {
+            let worst = results.peek().map(|Reverse(Candidate(score, _))| *score).unwrap_or(f32::MIN);
+            if results.len() >= ef && score < worst {
+                break;
+            }
+            if layer >= self.links[node].len() {
+                continue;
+            }
+            for &neighbour in self.links[node][layer].iter() {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+                let candidate = Candidate(dot(query, self.vector(neighbour)), neighbour);
+                let worst = results.peek().map(|Reverse(Candidate(score, _))| *score).unwrap_or(f32::MIN);
+                if results.len() < ef || candidate.0 > worst {
+                    candidates.push(candidate);
+                    if !(skip_deleted && self.deleted[neighbour]) {
+                        results.push(Reverse(candidate));
+                        if results.len() > ef {
+                            results.pop();
+                        }
+                    }
+                }
+            }
+        }
+
+        results.into_sorted_vec().into_iter().map(|Reverse(candidate)| candidate).collect()
+    }
+
+    fn search(&self, query: &[f32], k: usize) -> Vec<(u32, f32)> {
+        let mut entry = match self.entry {
+            Some(entry) => entry,
+            None => return Vec::new(),
+        };
+        for layer in (1..=self.max_level).rev() {
+            entry = self.search_layer(query, vec![entry], 1, layer, false)[0].1;
+        }
+        self.search_layer(query, vec![entry], self.ef_search.max(k), 0, true).into_iter()
+            .take(k)
+            .map(|Candidate(score, node)| (self.ids[node], score))
+            .collect()
+    }
+}
@@ -1,33 +1,185 @@
 use rust2vec::prelude::*;
+use rust2vec::vocab::WordIndex;
+use std::collections::HashSet;
+use std::fmt;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Cursor};
+use std::path::Path;
 use std::time::Instant;
 use memmap::Mmap;
+use crate::fingerprint::sampled_hash;
+use crate::format::EmbeddingFormat;
+use crate::weighting::{TermStats, Weighting, SIF_A};
+
+pub mod embedder;
+pub mod fingerprint;
+pub mod format;
+pub mod index;
+pub mod sentence;
+pub mod weighting;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmbeddingError {
+    /// None of the tokens in the text has a word or subword vector.
+    OutOfVocabulary(String),
+    /// The model files could not be loaded or the model failed to run.
+    Model(String),
+}
+
+impl fmt::Display for EmbeddingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmbeddingError::OutOfVocabulary(text) => write!(f, "no known words in \"{}\"", text),
+            EmbeddingError::Model(message) => write!(f, "embedding model error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for EmbeddingError {}
 
 pub struct Embedding {
-    embeddings: Embeddings<SimpleVocab, NdArray>,
+    embeddings: Embeddings<VocabWrap, StorageWrap>,
+    model_id: String,
+    weighting: Weighting,
+    stop_words: HashSet<String>,
+    term_stats: TermStats,
 }
 
 impl Embedding {
     pub fn new() -> Self {
+        let embeddings: Embeddings<SimpleVocab, NdArray> = Embeddings::new(None, SimpleVocab::new(vec!["<UNK>".to_owned()]), NdArray(Default::default()));
         Embedding {
-            embeddings: Embeddings::new(None, SimpleVocab::new(vec!["<UNK>".to_owned()]), NdArray(Default::default()))
+            embeddings: embeddings.into(),
+            model_id: "none".to_string(),
+            weighting: Weighting::Uniform,
+            stop_words: HashSet::new(),
+            term_stats: TermStats::new(),
         }
     }
 
-    pub fn get_embeddings(&mut self, path: &str) {
-        let start = Instant::now();
-        println!("Start loading embeddings");
-        let file = File::open(path).unwrap();
-        let mmap = unsafe { Mmap::map(&file).unwrap() };
+    pub fn weighting(&self) -> Weighting {
+        self.weighting
+    }
+
+    pub fn set_weighting(&mut self, weighting: Weighting) {
+        self.weighting = weighting;
+    }
+
+    /// Words in this set are left out of sentence vectors; an empty set keeps every word.
+    pub fn set_stop_words(&mut self, stop_words: HashSet<String>) {
+        self.stop_words = stop_words;
+    }
+
+    pub fn set_term_stats(&mut self, term_stats: TermStats) {
+        self.term_stats = term_stats;
+    }
+
+    /// Counts a newly indexed caption or label list so IDF and SIF weights stay current.
+    pub fn add_document(&mut self, tokens: &[String]) {
+        self.term_stats.add_document(tokens);
+    }
+
+    fn word_weight(&self, word: &str) -> f32 {
+        match self.weighting {
+            Weighting::Uniform => 1.0,
+            Weighting::Idf => self.term_stats.idf(word),
+            Weighting::Sif => self.term_stats.sif(word, SIF_A),
+        }
+    }
+
+    /// Loads the word vectors at `path`, detecting their format. On failure the previous
+    /// vectors are kept.
+    pub fn get_embeddings(&mut self, path: &str) -> Result<(), EmbeddingError> {
+        let mmap = Self::map(path)?;
+        let format = EmbeddingFormat::detect(path, &mmap);
+        self.load(&mmap, format).map_err(|e| EmbeddingError::Model(format!("{}: {}", path, e)))?;
+        self.model_id = Self::model_id_for(path, format, &mmap);
+        Ok(())
+    }
+
+    pub fn get_embeddings_with_format(&mut self, path: &str, format: EmbeddingFormat) -> Result<(), EmbeddingError> {
+        let mmap = Self::map(path)?;
+        self.load(&mmap, format).map_err(|e| EmbeddingError::Model(format!("{}: {}", path, e)))?;
+        self.model_id = Self::model_id_for(path, format, &mmap);
+        Ok(())
+    }
+
+    fn map(path: &str) -> Result<Mmap, EmbeddingError> {
+        let file = File::open(path).map_err(|e| EmbeddingError::Model(format!("{}: {}", path, e)))?;
+        unsafe { Mmap::map(&file) }.map_err(|e| EmbeddingError::Model(format!("{}: {}", path, e)))
+    }
+
+    /// Builds an id like `glove:glove.6B.300d:<hash>` from the format, the file name and a
+    /// hash of the contents, so another model saved under the same name gets another id.
+    fn model_id_for(path: &str, format: EmbeddingFormat, data: &[u8]) -> String {
+        let name = Path::new(path).file_stem().and_then(|stem| stem.to_str()).unwrap_or(path);
+        let kind = match format {
+            EmbeddingFormat::Glove => "glove",
+            EmbeddingFormat::TextWithHeader => "word2vec-text",
+            EmbeddingFormat::Word2VecBinary => "word2vec",
+            EmbeddingFormat::FinalFusion => "finalfusion",
+        };
+        format!("{}:{}:{}", kind, name, sampled_hash(&[data]))
+    }
+
+    pub fn dims(&self) -> usize {
+        self.embeddings.dims()
+    }
 
-        let mut reader = BufReader::new(&*mmap);
-        self.embeddings = Embeddings::read_text(&mut reader, true).unwrap();
+    fn load(&mut self, data: &[u8], format: EmbeddingFormat) -> Result<(), String> {
+        let start = Instant::now();
+        println!("Start loading embeddings ({:?})", format);
+        let mut reader = BufReader::new(Cursor::new(data));
+        self.embeddings = match format {
+            EmbeddingFormat::Glove => Embeddings::<SimpleVocab, NdArray>::read_text(&mut reader, true)
+                .map_err(|e| e.to_string())?.into(),
+            EmbeddingFormat::TextWithHeader => Embeddings::<SimpleVocab, NdArray>::read_text_dims(&mut reader, true)
+                .map_err(|e| e.to_string())?.into(),
+            EmbeddingFormat::Word2VecBinary => Embeddings::<SimpleVocab, NdArray>::read_word2vec_binary(&mut reader, true)
+                .map_err(|e| e.to_string())?.into(),
+            EmbeddingFormat::FinalFusion => Embeddings::<VocabWrap, StorageWrap>::read_embeddings(&mut reader)
+                .map_err(|e| e.to_string())?,
+        };
 
         println!("embeddings are loaded!!!\nTime: {:?}", start.elapsed());
+        if self.has_subwords() {
+            println!("Subword vocabulary found, unknown words fall back to character n-grams");
+        }
+        Ok(())
+    }
+
+    /// True when the loaded model carries fastText-style n-gram buckets.
+    pub fn has_subwords(&self) -> bool {
+        matches!(self.embeddings.vocab(), VocabWrap::SubwordVocab(_))
+    }
+
+    /// Looks up a word, composing it from its character n-grams when the word
+    /// itself is missing and the vocabulary has subwords.
+    fn word_embedding(&self, word: &str) -> Option<Vec<f32>> {
+        match self.embeddings.vocab().idx(word)? {
+            WordIndex::Word(idx) => Some(self.embeddings.storage().embedding(idx).as_view().to_vec()),
+            WordIndex::Subword(indices) => {
+                if indices.is_empty() {
+                    return None;
+                }
+                let mut vector = vec![0.0; self.embeddings.dims()];
+                for idx in indices {
+                    for (i, value) in self.embeddings.storage().embedding(idx).as_view().iter().enumerate() {
+                        vector[i] += *value;
+                    }
+                }
+                let norm = vector.iter().map(|a| a.powi(2)).sum::<f32>().sqrt();
+                if norm > 0.0 {
+                    for value in &mut vector {
+                        *value /= norm;
+                    }
+                }
+                Some(vector)
+            }
+        }
     }
 
-    fn prepare_text(text: &str) -> Vec<String> {
+    pub fn prepare_text(text: &str) -> Vec<String> {
         let mut tokens: String = String::new();
         for i in text.chars() {
             if i.is_alphabetic() || i == ' ' {
@@ -38,60 +190,78 @@ impl Embedding {
         tokens = tokens.to_lowercase();
         tokens.split_whitespace().map(|s| s.to_string()).collect()
     }
-    pub fn average_vector(&mut self, sentence: &str) -> Vec<f32> {
-        let words: Vec<String> = Self::prepare_text(sentence);
+    pub fn average_vector(&self, sentence: &str) -> Result<Vec<f32>, EmbeddingError> {
+        let mut words: Vec<String> = Self::prepare_text(sentence);
+        let content_words: Vec<String> = words.iter().filter(|word| !self.stop_words.contains(*word)).cloned().collect();
+        // A query made only of stop words ("the one") still gets a vector.
+        if !content_words.is_empty() {
+            words = content_words;
+        }
         let mut vector = vec![0.0; self.embeddings.dims()];
-        let mut count = 0;
+        let mut total_weight = 0.0;
 
         for word in words {
-            if let Some(embedding) = self.embeddings.embedding(word.as_str()) {
-                for (i, value) in embedding.as_view().iter().enumerate() {
-                    vector[i] += *value;
+            if let Some(embedding) = self.word_embedding(word.as_str()) {
+                let weight = self.word_weight(word.as_str());
+                for (i, value) in embedding.iter().enumerate() {
+                    vector[i] += weight * *value;
                 }
-                count += 1;
+                total_weight += weight;
             }
         }
 
-        if count > 0 {
-            for value in &mut vector {
-                *value /= count as f32;
-            }
+        if total_weight == 0.0 {
+            return Err(EmbeddingError::OutOfVocabulary(sentence.to_string()));
+        }
+        for value in &mut vector {
+            *value /= total_weight;
         }
 
-        vector
+        Ok(vector)
     }
 
+    /// Returns 0.0 instead of NaN when either vector has no length.
     pub fn cosine_similarity(vector1: &[f32], vector2: &[f32]) -> f32 {
         let dot_product: f32 = vector1.iter().zip(vector2).map(|(a, b)| a * b).sum();
         let magnitude1: f32 = vector1.iter().map(|a| a.powi(2)).sum::<f32>().sqrt();
         let magnitude2: f32 = vector2.iter().map(|a| a.powi(2)).sum::<f32>().sqrt();
 
+        if magnitude1 == 0.0 || magnitude2 == 0.0 {
+            return 0.0;
+        }
         dot_product / (magnitude1 * magnitude2)
     }
 
-    pub fn semantic_vector(&mut self, phrases: Vec<&str>) -> Vec<f32> {
+    /// Averages the phrases that have at least one known word; fails only when none do.
+    pub fn semantic_vector(&self, phrases: Vec<&str>) -> Result<Vec<f32>, EmbeddingError> {
         let mut sum_vector = vec![0.0; self.embeddings.dims()];
         let mut count = 0;
 
-        for phrase in phrases {
-            let vector = self.average_vector(phrase);
+        for phrase in phrases.iter() {
+            let vector = match self.average_vector(phrase) {
+                Ok(vector) => vector,
+                Err(_) => continue,
+            };
             for i in 0..vector.len() {
                 sum_vector[i] += vector[i];
             }
             count += 1;
         }
 
+        if count == 0 {
+            return Err(EmbeddingError::OutOfVocabulary(phrases.join(" ")));
+        }
         for i in 0..sum_vector.len() {
             sum_vector[i] /= count as f32;
         }
 
-        sum_vector
+        Ok(sum_vector)
     }
 
-    pub(crate) fn similarity_string(&mut self, phrase1: &str, phrase2: &str) -> f32 {
-        let vector1 = self.average_vector(phrase1);
-        let vector2 = self.average_vector(phrase2);
+    pub(crate) fn similarity_string(&self, phrase1: &str, phrase2: &str) -> Result<f32, EmbeddingError> {
+        let vector1 = self.average_vector(phrase1)?;
+        let vector2 = self.average_vector(phrase2)?;
 
-        Embedding::cosine_similarity(&vector1, &vector2)
+        Ok(Embedding::cosine_similarity(&vector1, &vector2))
     }
 }
\ No newline at end of file
@@ -0,0 +1,132 @@
+use std::fs::File;
+use std::path::Path;
+use std::time::Instant;
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+use tokenizers::{PaddingParams, Tokenizer, TruncationParams};
+use memmap::Mmap;
+use crate::embedder::TextEmbedder;
+use crate::fingerprint::sampled_hash;
+use crate::EmbeddingError;
+
+const MAX_TOKENS: usize = 256;
+
+/// A BERT-style sentence-embedding model (e.g. all-MiniLM-L6-v2) run on the CPU.
+///
+/// The model directory needs `config.json`, `tokenizer.json` and `model.safetensors`
+/// as published by sentence-transformers. Sentences are mean-pooled over their tokens
+/// and L2-normalised.
+pub struct SentenceEmbedder {
+    model: BertModel,
+    tokenizer: Tokenizer,
+    device: Device,
+    model_id: String,
+    dimensions: usize,
+}
+
+impl SentenceEmbedder {
+    pub fn load(dir: &str) -> Result<SentenceEmbedder, EmbeddingError> {
+        let start = Instant::now();
+        println!("Start loading sentence model from {}", dir);
+        let dir_path = Path::new(dir);
+        let device = Device::Cpu;
+
+        let config = std::fs::read_to_string(dir_path.join("config.json"))
+            .map_err(|e| EmbeddingError::Model(format!("config.json: {}", e)))?;
+        let config: Config = serde_json::from_str(&config)
+            .map_err(|e| EmbeddingError::Model(format!("config.json: {}", e)))?;
+        let dimensions = config.hidden_size;
+
+        let mut tokenizer = Tokenizer::from_file(dir_path.join("tokenizer.json"))
+            .map_err(|e| EmbeddingError::Model(format!("tokenizer.json: {}", e)))?;
+        tokenizer.with_padding(Some(PaddingParams::default()));
+        tokenizer.with_truncation(Some(TruncationParams { max_length: MAX_TOKENS, ..Default::default() }))
+            .map_err(|e| EmbeddingError::Model(format!("tokenizer.json: {}", e)))?;
+
+        let weights = dir_path.join("model.safetensors");
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[weights], DTYPE, &device) }
+            .map_err(|e| EmbeddingError::Model(format!("model.safetensors: {}", e)))?;
+        let model = BertModel::load(vb, &config)
+            .map_err(|e| EmbeddingError::Model(format!("model.safetensors: {}", e)))?;
+
+        let model_id = Self::model_id_for(dir)?;
+        println!("sentence model {} is loaded!!!\nTime: {:?}", model_id, start.elapsed());
+        Ok(SentenceEmbedder {
+            model,
+            tokenizer,
+            device,
+            model_id,
+            dimensions,
+        })
+    }
+
+    /// Builds an id like `sentence:all-MiniLM-L6-v2-384d:<hash>` from the name and size in the
+    /// model directory's `config.json` and a hash of its config, tokenizer and weights, so
+    /// another model dropped into the same directory gets another id.
+    pub fn model_id_for(dir: &str) -> Result<String, EmbeddingError> {
+        let dir = Path::new(dir);
+        let config_json = std::fs::read(dir.join("config.json"))
+            .map_err(|e| EmbeddingError::Model(format!("config.json: {}", e)))?;
+        let config: serde_json::Value = serde_json::from_slice(&config_json)
+            .map_err(|e| EmbeddingError::Model(format!("config.json: {}", e)))?;
+        let name = config.get("_name_or_path")
+            .and_then(|name| name.as_str())
+            .and_then(|name| name.trim_end_matches('/').rsplit('/').next())
+            .filter(|name| !name.is_empty())
+            .or_else(|| config.get("model_type").and_then(|kind| kind.as_str()))
+            .unwrap_or("bert");
+        let dimensions = config.get("hidden_size").and_then(|size| size.as_u64()).unwrap_or(0);
+        let tokenizer = std::fs::read(dir.join("tokenizer.json"))
+            .map_err(|e| EmbeddingError::Model(format!("tokenizer.json: {}", e)))?;
+        let weights = File::open(dir.join("model.safetensors"))
+            .and_then(|file| unsafe { Mmap::map(&file) })
+            .map_err(|e| EmbeddingError::Model(format!("model.safetensors: {}", e)))?;
+        let hash = sampled_hash(&[&config_json, &tokenizer, &weights]);
+        Ok(format!("sentence:{}-{}d:{}", name, dimensions, hash))
+    }
+
+    fn forward(&self, texts: &[&str]) -> candle_core::Result<Vec<Vec<f32>>> {
+        let encodings = self.tokenizer.encode_batch(texts.to_vec(), true)
+            .map_err(|e| candle_core::Error::Msg(e.to_string()))?;
+        let mut ids = Vec::with_capacity(encodings.len());
+        let mut masks = Vec::with_capacity(encodings.len());
+        for encoding in encodings.iter() {
+            ids.push(Tensor::new(encoding.get_ids(), &self.device)?);
+            masks.push(Tensor::new(encoding.get_attention_mask(), &self.device)?);
+        }
+        let ids = Tensor::stack(&ids, 0)?;
+        let mask = Tensor::stack(&masks, 0)?;
+        let token_type_ids = ids.zeros_like()?;
+
+        let hidden = self.model.forward(&ids, &token_type_ids, Some(&mask))?;
+        let mask = mask.to_dtype(DType::F32)?.unsqueeze(2)?;
+        let summed = hidden.broadcast_mul(&mask)?.sum(1)?;
+        let counts = mask.sum(1)?.clamp(1e-9, f64::MAX)?;
+        let pooled = summed.broadcast_div(&counts)?;
+        let norms = pooled.sqr()?.sum_keepdim(1)?.sqrt()?.clamp(1e-12, f64::MAX)?;
+        pooled.broadcast_div(&norms)?.to_vec2::<f32>()
+    }
+}
+
+impl TextEmbedder for SentenceEmbedder {
+    fn model_id(&self) -> String {
+        self.model_id.clone()
+    }
+
+    fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>, EmbeddingError> {
+        let mut vectors = self.embed_batch(&[text])?;
+        Ok(vectors.remove(0))
+    }
+
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>, EmbeddingError> {
+        if texts.iter().any(|text| text.trim().is_empty()) {
+            return Err(EmbeddingError::OutOfVocabulary(texts.join(" ")));
+        }
+        self.forward(texts).map_err(|e| EmbeddingError::Model(e.to_string()))
+    }
+}
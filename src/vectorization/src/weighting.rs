@@ -0,0 +1,119 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+/// How the words of a sentence are weighted when they are averaged into one vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Weighting {
+    Uniform,
+    /// Smoothed inverse document frequency over the indexed captions and labels.
+    Idf,
+    /// Smooth inverse frequency, `a / (a + p(w))`, with `p(w)` the word's share of all indexed tokens.
+    Sif,
+}
+
+impl Weighting {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Weighting::Uniform => "uniform",
+            Weighting::Idf => "idf",
+            Weighting::Sif => "sif",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Weighting> {
+        match value {
+            "uniform" => Some(Weighting::Uniform),
+            "idf" => Some(Weighting::Idf),
+            "sif" => Some(Weighting::Sif),
+            _ => None,
+        }
+    }
+}
+
+pub const SIF_A: f32 = 1e-3;
+
+const ENGLISH_STOP_WORDS: &[&str] = &[
+    "a", "about", "above", "after", "again", "against", "all", "am", "an", "and", "any", "are", "as", "at",
+    "be", "because", "been", "before", "being", "below", "between", "both", "but", "by",
+    "can", "could", "did", "do", "does", "doing", "down", "during", "each", "few", "for", "from", "further",
+    "had", "has", "have", "having", "he", "her", "here", "hers", "herself", "him", "himself", "his", "how",
+    "i", "if", "in", "into", "is", "it", "its", "itself", "just", "me", "more", "most", "my", "myself",
+    "no", "nor", "not", "now", "of", "off", "on", "once", "only", "or", "other", "our", "ours", "ourselves", "out", "over", "own",
+    "same", "she", "should", "so", "some", "such", "than", "that", "the", "their", "theirs", "them", "themselves",
+    "then", "there", "these", "they", "this", "those", "through", "to", "too", "under", "until", "up",
+    "very", "was", "we", "were", "what", "when", "where", "which", "while", "who", "whom", "why", "will", "with",
+    "would", "you", "your", "yours", "yourself", "yourselves",
+];
+
+pub fn english_stop_words() -> HashSet<String> {
+    ENGLISH_STOP_WORDS.iter().map(|word| word.to_string()).collect()
+}
+
+/// Reads a stop-word list with one word per line; empty lines and `#` comments are ignored.
+pub fn read_stop_words(path: &str) -> Result<HashSet<String>, std::io::Error> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut words = HashSet::new();
+    for line in reader.lines() {
+        let line = line?;
+        let word = line.trim();
+        if word.is_empty() || word.starts_with('#') {
+            continue;
+        }
+        words.insert(word.to_lowercase());
+    }
+    Ok(words)
+}
+
+/// Document and token counts collected from the indexed captions and labels.
+#[derive(Debug, Clone, Default)]
+pub struct TermStats {
+    documents: u32,
+    total_terms: u64,
+    terms: HashMap<String, (u32, u32)>,
+}
+
+impl TermStats {
+    pub fn new() -> Self {
+        TermStats::default()
+    }
+
+    pub fn set_documents(&mut self, documents: u32) {
+        self.documents = documents;
+    }
+
+    /// Records one term with the number of documents it appears in and its total occurrences.
+    pub fn insert(&mut self, term: String, document_count: u32, term_count: u32) {
+        self.total_terms += term_count as u64;
+        if let Some((_, old_count)) = self.terms.insert(term, (document_count, term_count)) {
+            self.total_terms -= old_count as u64;
+        }
+    }
+
+    pub fn add_document(&mut self, tokens: &[String]) {
+        let mut seen = HashSet::new();
+        for token in tokens {
+            let entry = self.terms.entry(token.clone()).or_insert((0, 0));
+            if seen.insert(token) {
+                entry.0 += 1;
+            }
+            entry.1 += 1;
+            self.total_terms += 1;
+        }
+        self.documents += 1;
+    }
+
+    pub fn idf(&self, term: &str) -> f32 {
+        let document_count = self.terms.get(term).map(|(docs, _)| *docs).unwrap_or(0);
+        ((1.0 + self.documents as f32) / (1.0 + document_count as f32)).ln() + 1.0
+    }
+
+    pub fn sif(&self, term: &str, a: f32) -> f32 {
+        if self.total_terms == 0 {
+            return 1.0;
+        }
+        let term_count = self.terms.get(term).map(|(_, count)| *count).unwrap_or(0);
+        let probability = term_count as f32 / self.total_terms as f32;
+        a / (a + probability)
+    }
+}
@@ -0,0 +1,102 @@
+#![allow(deprecated)]
+
+use std::path::PathBuf;
+use rust2vec::prelude::*;
+use vectorization::embedder::TextEmbedder;
+use vectorization::format::EmbeddingFormat;
+use vectorization::Embedding;
+
+const GLOVE: &[u8] = b"cat 0.1 0.2 0.3\ndog 0.2 0.1 0.3\n";
+const TEXT_WITH_HEADER: &[u8] = b"2 3\ncat 0.1 0.2 0.3\ndog 0.2 0.1 0.3\n";
+
+fn word2vec_binary() -> Vec<u8> {
+    let mut data = b"2 3\n".to_vec();
+    for (word, values) in [("cat", [0.1f32, 0.2, 0.3]), ("dog", [0.2, 0.1, 0.3])] {
+        data.extend_from_slice(word.as_bytes());
+        data.push(b' ');
+        for value in values {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.push(b'\n');
+    }
+    data
+}
+
+fn finalfusion() -> Vec<u8> {
+    let text: Embeddings<SimpleVocab, NdArray> = Embeddings::read_text_dims(&mut &TEXT_WITH_HEADER[..], false).unwrap();
+    let embeddings: Embeddings<VocabWrap, StorageWrap> = text.into();
+    let mut data = Vec::new();
+    embeddings.write_embeddings(&mut std::io::Cursor::new(&mut data)).unwrap();
+    data
+}
+
+fn write_temp(name: &str, data: &[u8]) -> String {
+    let dir = std::env::temp_dir().join(format!("vectorization-format-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path: PathBuf = dir.join(name);
+    std::fs::write(&path, data).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+#[test]
+fn detects_each_format_from_its_contents() {
+    assert_eq!(EmbeddingFormat::detect("glove.6B.300d.txt", GLOVE), EmbeddingFormat::Glove);
+    assert_eq!(EmbeddingFormat::detect("wiki.en.vec", TEXT_WITH_HEADER), EmbeddingFormat::TextWithHeader);
+    assert_eq!(EmbeddingFormat::detect("GoogleNews.bin", &word2vec_binary()), EmbeddingFormat::Word2VecBinary);
+    assert_eq!(EmbeddingFormat::detect("model.fifu", &finalfusion()), EmbeddingFormat::FinalFusion);
+}
+
+#[test]
+fn contents_win_over_a_mismatched_extension() {
+    assert_eq!(EmbeddingFormat::detect("vectors.bin", TEXT_WITH_HEADER), EmbeddingFormat::TextWithHeader);
+    assert_eq!(EmbeddingFormat::detect("vectors.bin", GLOVE), EmbeddingFormat::Glove);
+    assert_eq!(EmbeddingFormat::detect("vectors.txt", &word2vec_binary()), EmbeddingFormat::Word2VecBinary);
+    assert_eq!(EmbeddingFormat::detect("vectors.txt", &finalfusion()), EmbeddingFormat::FinalFusion);
+}
+
+#[test]
+fn inconclusive_contents_fall_back_to_the_extension() {
+    // A header with a truncated first record could be either layout.
+    let truncated = b"2 300\ncat 0.1";
+    assert_eq!(EmbeddingFormat::detect("vectors.bin", truncated), EmbeddingFormat::Word2VecBinary);
+    assert_eq!(EmbeddingFormat::detect("vectors.vec", truncated), EmbeddingFormat::TextWithHeader);
+    assert_eq!(EmbeddingFormat::detect("vectors", b""), EmbeddingFormat::Glove);
+}
+
+#[test]
+fn loads_every_detected_format() {
+    let files = [
+        ("glove.txt", GLOVE.to_vec()),
+        ("header.bin", TEXT_WITH_HEADER.to_vec()),
+        ("binary.txt", word2vec_binary()),
+        ("model.fifu", finalfusion()),
+    ];
+    for (name, data) in files {
+        let mut embedding = Embedding::new();
+        embedding.get_embeddings(&write_temp(name, &data)).unwrap();
+        assert_eq!(embedding.dims(), 3, "{}", name);
+        let cat = embedding.embed("cat").unwrap();
+        let dog = embedding.embed("dog").unwrap();
+        assert!(Embedding::cosine_similarity(&cat, &dog) < 0.99, "{}", name);
+    }
+}
+
+#[test]
+fn unreadable_files_are_errors_and_keep_the_loaded_vectors() {
+    let mut embedding = Embedding::new();
+    embedding.get_embeddings(&write_temp("loaded.txt", GLOVE)).unwrap();
+    let model_id = embedding.model_id();
+    let fifu = finalfusion();
+    let files = [
+        ("garbled.txt", b"cat 0.1 0.2 0.3\ndog 0.2 x 0.3\n".to_vec()),
+        ("truncated.fifu", fifu[..fifu.len() / 2].to_vec()),
+    ];
+
+    assert!(embedding.get_embeddings("/nonexistent/vectors.txt").is_err());
+    for (name, data) in files {
+        assert!(embedding.get_embeddings(&write_temp(name, &data)).is_err(), "{}", name);
+    }
+
+    assert_eq!(embedding.model_id(), model_id);
+    assert!(embedding.embed("cat").is_ok());
+}
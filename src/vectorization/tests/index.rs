@@ -0,0 +1,155 @@
+use std::collections::HashSet;
+use vectorization::index::{VectorIndex, WrongDimensions, HNSW_MAX_TOMBSTONES_PER, HNSW_MIN_VECTORS};
+
+const DIMENSIONS: usize = 32;
+
+/// Deterministic vectors spread over the sphere, so runs are repeatable without a rand dependency.
+fn vectors(count: usize, seed: u64) -> Vec<(u32, Vec<f32>)> {
+    let mut state = seed;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+    };
+    (0..count as u32).map(|id| (id, (0..DIMENSIONS).map(|_| next()).collect())).collect()
+}
+
+fn ids(hits: &[(u32, f32)]) -> Vec<u32> {
+    hits.iter().map(|(id, _)| *id).collect()
+}
+
+fn is_hnsw(index: &VectorIndex) -> bool {
+    matches!(index, VectorIndex::Hnsw(_))
+}
+
+#[test]
+fn small_libraries_get_a_flat_index_and_large_ones_hnsw() {
+    let items = vectors(100, 1);
+    assert!(!is_hnsw(&VectorIndex::build(DIMENSIONS, items.clone(), HNSW_MIN_VECTORS)));
+    assert!(!is_hnsw(&VectorIndex::build(DIMENSIONS, items.clone(), 101)));
+    let hnsw = VectorIndex::build(DIMENSIONS, items, 100);
+    assert!(is_hnsw(&hnsw));
+    assert_eq!(hnsw.len(), 100);
+}
+
+#[test]
+fn flat_search_finds_the_exact_neighbours() {
+    let items = vectors(200, 2);
+    let index = VectorIndex::build(DIMENSIONS, items.clone(), HNSW_MIN_VECTORS);
+
+    let hits = index.search(&items[42].1, 5);
+
+    assert_eq!(hits.len(), 5);
+    assert_eq!(hits[0].0, 42);
+    assert!((hits[0].1 - 1.0).abs() < 1e-5);
+    assert!(hits.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+}
+
+#[test]
+fn hnsw_recall_matches_the_flat_index() {
+    let items = vectors(2000, 3);
+    let flat = VectorIndex::build(DIMENSIONS, items.clone(), HNSW_MIN_VECTORS);
+    let hnsw = VectorIndex::build(DIMENSIONS, items, 0);
+    let k = 10;
+
+    let mut found = 0;
+    let queries = vectors(50, 4);
+    for (_, query) in &queries {
+        let exact: HashSet<u32> = ids(&flat.search(query, k)).into_iter().collect();
+        found += ids(&hnsw.search(query, k)).iter().filter(|id| exact.contains(id)).count();
+    }
+    let recall = found as f32 / (queries.len() * k) as f32;
+    assert!(recall >= 0.9, "recall {}", recall);
+}
+
+#[test]
+fn inserts_replace_existing_vectors() {
+    for min_vectors in [HNSW_MIN_VECTORS, 0] {
+        let items = vectors(300, 5);
+        let mut index = VectorIndex::build(DIMENSIONS, items.clone(), min_vectors);
+        let moved = items[7].1.clone();
+
+        index.insert(3, &moved).unwrap();
+
+        assert_eq!(index.len(), 300);
+        let hits = ids(&index.search(&moved, 2));
+        assert!(hits.contains(&3) && hits.contains(&7), "{:?}", hits);
+        assert_ne!(index.search(&items[3].1, 1)[0].0, 3);
+    }
+}
+
+#[test]
+fn vectors_of_the_wrong_size_are_ignored() {
+    let mut index = VectorIndex::build(DIMENSIONS, vectors(10, 6), HNSW_MIN_VECTORS);
+    let refused = index.insert(99, &[1.0; DIMENSIONS + 1]).unwrap_err();
+    assert_eq!(refused, WrongDimensions { id: 99, found: DIMENSIONS + 1, expected: DIMENSIONS });
+    assert_eq!(index.len(), 10);
+    assert!(index.search(&[1.0; DIMENSIONS - 1], 5).is_empty());
+}
+
+#[test]
+fn removed_vectors_leave_the_results() {
+    for min_vectors in [HNSW_MIN_VECTORS, 0] {
+        let items = vectors(300, 7);
+        let mut index = VectorIndex::build(DIMENSIONS, items.clone(), min_vectors);
+
+        index.remove(11);
+        index.remove(11);
+
+        assert_eq!(index.len(), 299);
+        assert!(!ids(&index.search(&items[11].1, 20)).contains(&11));
+        assert_eq!(index.search(&items[12].1, 1)[0].0, 12);
+    }
+}
+
+#[test]
+fn rebuilding_gives_the_same_results() {
+    let items = vectors(500, 8);
+    let mut index = VectorIndex::build(DIMENSIONS, items.clone(), 0);
+    for id in 0..100 {
+        index.remove(id);
+    }
+    let rebuilt = VectorIndex::build(DIMENSIONS, items[100..].to_vec(), 0);
+
+    assert_eq!(rebuilt.len(), index.len());
+    for (_, query) in vectors(10, 9) {
+        assert_eq!(ids(&index.search(&query, 1)), ids(&rebuilt.search(&query, 1)));
+    }
+}
+
+#[test]
+fn replacing_vectors_does_not_grow_the_graph() {
+    let items = vectors(300, 10);
+    let mut index = VectorIndex::build(DIMENSIONS, items.clone(), 0);
+    for round in 0..5u64 {
+        for (id, vector) in vectors(300, 11 + round) {
+            index.insert(id, &vector).unwrap();
+        }
+    }
+
+    assert_eq!(index.len(), 300);
+    let nodes = match &index {
+        VectorIndex::Hnsw(hnsw) => hnsw.node_count(),
+        VectorIndex::Flat(_) => unreachable!(),
+    };
+    assert!(nodes <= 300 + 300 / (HNSW_MAX_TOMBSTONES_PER - 1) + 1, "{} nodes", nodes);
+    let last = vectors(300, 15);
+    assert_eq!(index.search(&last[20].1, 1)[0].0, 20);
+}
+
+#[test]
+fn removed_neighbours_do_not_shorten_the_results() {
+    let items = vectors(400, 12);
+    let mut index = VectorIndex::build(DIMENSIONS, items.clone(), 0);
+    let query = &items[0].1;
+    // Removing the closest 80 leaves tombstones around the query without a rebuild.
+    for (id, _) in index.search(query, 80) {
+        index.remove(id);
+    }
+
+    let hits = index.search(query, 10);
+
+    assert_eq!(hits.len(), 10);
+    assert_eq!(index.len(), 320);
+}
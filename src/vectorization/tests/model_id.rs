@@ -0,0 +1,47 @@
+use vectorization::embedder::TextEmbedder;
+use vectorization::weighting::{english_stop_words, Weighting};
+use vectorization::Embedding;
+
+#[test]
+fn weighting_and_stop_words_are_part_of_the_model_id() {
+    let mut embedding = Embedding::new();
+    let uniform = embedding.model_id();
+
+    embedding.set_weighting(Weighting::Idf);
+    let idf = embedding.model_id();
+    embedding.set_weighting(Weighting::Sif);
+    let sif = embedding.model_id();
+    embedding.set_stop_words(english_stop_words());
+    let sif_english = embedding.model_id();
+
+    let ids = [&uniform, &idf, &sif, &sif_english];
+    for (i, a) in ids.iter().enumerate() {
+        for b in ids.iter().skip(i + 1) {
+            assert_ne!(a, b);
+        }
+    }
+
+    embedding.set_weighting(Weighting::Uniform);
+    embedding.set_stop_words(Default::default());
+    assert_eq!(embedding.model_id(), uniform);
+}
+
+fn load(name: &str, contents: &str) -> Embedding {
+    let dir = std::env::temp_dir().join(format!("vectorization-model-id-{}-{}", std::process::id(), name));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("vectors.txt");
+    std::fs::write(&path, contents).unwrap();
+    let mut embedding = Embedding::new();
+    embedding.get_embeddings(path.to_str().unwrap()).unwrap();
+    embedding
+}
+
+#[test]
+fn another_model_under_the_same_name_gets_another_id() {
+    let first = load("first", "cat 0.1 0.2 0.3\ndog 0.2 0.1 0.3\n");
+    let same = load("same", "cat 0.1 0.2 0.3\ndog 0.2 0.1 0.3\n");
+    let other = load("other", "cat 0.3 0.2 0.1\ndog 0.2 0.3 0.1\n");
+    assert!(first.model_id().starts_with("glove:vectors:"), "{}", first.model_id());
+    assert_eq!(first.model_id(), same.model_id());
+    assert_ne!(first.model_id(), other.model_id());
+}
@@ -0,0 +1,30 @@
+use std::path::PathBuf;
+use vectorization::sentence::SentenceEmbedder;
+
+const CONFIG: &str = r#"{"_name_or_path": "sentence-transformers/all-MiniLM-L6-v2", "model_type": "bert", "hidden_size": 384}"#;
+
+fn model_dir(name: &str, config: &str, weights: &[u8]) -> String {
+    let dir: PathBuf = std::env::temp_dir().join(format!("vectorization-sentence-{}-{}", std::process::id(), name));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("config.json"), config).unwrap();
+    std::fs::write(dir.join("tokenizer.json"), "{}").unwrap();
+    std::fs::write(dir.join("model.safetensors"), weights).unwrap();
+    dir.to_str().unwrap().to_string()
+}
+
+#[test]
+fn id_names_the_model_and_its_size() {
+    let id = SentenceEmbedder::model_id_for(&model_dir("named", CONFIG, b"weights")).unwrap();
+    assert!(id.starts_with("sentence:all-MiniLM-L6-v2-384d:"), "{}", id);
+}
+
+#[test]
+fn other_weights_of_the_same_size_get_another_id() {
+    let first = SentenceEmbedder::model_id_for(&model_dir("first", CONFIG, &vec![1u8; 5_000_000])).unwrap();
+    let same = SentenceEmbedder::model_id_for(&model_dir("same", CONFIG, &vec![1u8; 5_000_000])).unwrap();
+    let mut weights = vec![1u8; 5_000_000];
+    weights[4_999_999] = 2;
+    let other = SentenceEmbedder::model_id_for(&model_dir("other", CONFIG, &weights)).unwrap();
+    assert_eq!(first, same);
+    assert_ne!(first, other);
+}